@@ -0,0 +1,570 @@
+//! Two pass assembler turning Rusty VM assembly into a program
+//!
+//! Each line holds optional `label:` definitions followed by an optional
+//! instruction. Operands are separated by commas:
+//!
+//! ```text
+//! start:
+//!     push 10i32          ; integers default to i32, floats to f64
+//!     ld_imm r1, 2.5f32
+//!     out 0, "done\n"
+//!     jnz start
+//! ```
+//!
+//! Compound literals are written `[1, 2]` for arrays, `(1, 'a')` for
+//! tuples and `{"key": 1}` for maps.
+//!
+//! Labels starting with `.` are local: they can be jumped to but are not
+//! exported as VM symbols. The directives `.value <literal>`, `.tag "text"`
+//! and `.empty` place non-instruction cells in memory.
+
+use std::{
+    collections::HashMap,
+    rc::Rc,
+};
+
+use rusty_vm::rvm::{
+    builder::VMBuilder,
+    types::ValueType,
+    vm::{instruction_table, Instruction, MemoryCell, Message, MetaData, Value, REGISTER_COUNT},
+};
+
+use crate::{
+    error::AsmError,
+    lexer::{tokenize, Span, Token},
+};
+
+
+/// A parsed line item
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Label(String, Span),
+    /// An instruction and, for branches written with a label, the label to resolve
+    Instruction(Instruction, Option<(String, Span)>, Span),
+    /// A memory cell placed by a directive
+    Data(MemoryCell, Span),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Register(usize),
+    Label(String),
+    Value(Value),
+}
+
+/// Assembles `source` into a built program ready to `start`
+pub fn assemble(source: &str) -> Result<VMBuilder, AsmError> {
+    let statements = parse(source)?;
+
+    let mut labels: HashMap<&str, Span> = HashMap::new();
+    for statement in &statements {
+        if let Statement::Label(name, span) = statement {
+            if let Some(first) = labels.insert(name, *span) {
+                return Err(AsmError::new(*span, format!("label '{}' is already defined at {}:{}",
+                                                        name, first.line, first.column)));
+            }
+        }
+    }
+
+    let mut builder = VMBuilder::new();
+    for statement in &statements {
+        match statement {
+            Statement::Label(name, _) if name.starts_with('.') => {
+                builder.local_label(name);
+            },
+            Statement::Label(name, _) => {
+                builder.label(name);
+            },
+            Statement::Data(cell, _) => {
+                builder.cell(cell.clone());
+            },
+            Statement::Instruction(inst, None, _) => {
+                builder.instruction(inst.clone());
+            },
+            Statement::Instruction(inst, Some((label, span)), _) => {
+                if !labels.contains_key(label.as_str()) {
+                    return Err(AsmError::new(*span, format!("undefined label '{}'", label)));
+                }
+                builder.instruction_to(inst.clone(), label);
+            }
+        }
+    }
+    builder.build().map_err(|err| AsmError::new(Span { line: 1, column: 1 }, err.to_string()))?;
+    Ok(builder)
+}
+
+/// Parses `source` into labels and instructions without resolving labels
+pub fn parse(source: &str) -> Result<Vec<Statement>, AsmError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let mut statements = vec![];
+    while parser.pos < parser.tokens.len() {
+        parser.line(&mut statements)?;
+    }
+    Ok(statements)
+}
+
+/// Parses a single literal operand such as `42i64`, `"text"` or `@null`
+pub fn parse_value(text: &str) -> Result<Value, AsmError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let value = match parser.operand()? {
+        (Operand::Value(value), _) => value,
+        (_, span) => return Err(AsmError::new(span, "expected a literal value")),
+    };
+    match parser.next() {
+        (Token::Newline, _) if parser.pos == parser.tokens.len() => Ok(value),
+        (tok, span) => Err(AsmError::new(span, format!("unexpected {} after value", describe(&tok)))),
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &(Token, Span) {
+        // the token stream always ends with a newline
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> (Token, Span) {
+        let tok = self.peek().clone();
+        self.pos += 1;
+        tok
+    }
+
+    fn line(&mut self, statements: &mut Vec<Statement>) -> Result<(), AsmError> {
+        loop {
+            match self.next() {
+                (Token::Newline, _) => return Ok(()),
+                (Token::Ident(name), span) => {
+                    if self.peek().0 == Token::Colon {
+                        self.next();
+                        statements.push(Statement::Label(name, span));
+                        continue;
+                    }
+                    let operands = self.operands()?;
+                    if name.starts_with('.') {
+                        statements.push(Statement::Data(directive(&name, span, &operands)?, span));
+                    } else {
+                        let (inst, target) = instruction(&name, span, &operands)?;
+                        statements.push(Statement::Instruction(inst, target, span));
+                    }
+                    return match self.next() {
+                        (Token::Newline, _) => Ok(()),
+                        (tok, span) => Err(AsmError::new(span, format!("unexpected {} after instruction", describe(&tok)))),
+                    };
+                },
+                (tok, span) => return Err(AsmError::new(span, format!("expected a label or instruction, found {}", describe(&tok)))),
+            }
+        }
+    }
+
+    fn operands(&mut self) -> Result<Vec<(Operand, Span)>, AsmError> {
+        let mut operands = vec![];
+        if self.peek().0 == Token::Newline {
+            return Ok(operands);
+        }
+        loop {
+            operands.push(self.operand()?);
+            if self.peek().0 != Token::Comma {
+                return Ok(operands);
+            }
+            self.next();
+        }
+    }
+
+    fn operand(&mut self) -> Result<(Operand, Span), AsmError> {
+        let (tok, span) = self.next();
+        let operand = match tok {
+            Token::Ident(name) => match name.as_str() {
+                "true" => Operand::Value(Value::Bool(true)),
+                "false" => Operand::Value(Value::Bool(false)),
+                _ if is_float_keyword(&name) => Operand::Value(parse_number(&name, span)?),
+                _ => match parse_register(&name) {
+                    Some(reg) => Operand::Register(reg),
+                    None => Operand::Label(name),
+                }
+            },
+            Token::Number(text) => Operand::Value(parse_number(&text, span)?),
+            Token::Char(c) => Operand::Value(Value::Char(c)),
+            Token::Str(s) => Operand::Value(Value::String(s)),
+            Token::Bytes(bytes) => Operand::Value(Value::Bytes(bytes)),
+            Token::At => match self.next() {
+                (Token::Number(text), span) => match text.parse::<usize>() {
+                    Ok(address) => Operand::Value(Value::Address(Some(address))),
+                    Err(_) => return Err(AsmError::new(span, format!("invalid address '{}'", text))),
+                },
+                (Token::Ident(name), _) if name == "null" => Operand::Value(Value::Address(None)),
+                (tok, span) => return Err(AsmError::new(span, format!("expected an address after '@', found {}", describe(&tok)))),
+            },
+            Token::Hash => Operand::Value(Value::Symbol(Rc::new(self.literal()?))),
+            Token::Open('[') => Operand::Value(Value::Array(self.items(']')?)),
+            Token::Open('(') => Operand::Value(Value::Tuple(self.items(')')?)),
+            Token::Open('{') => Operand::Value(Value::Map(self.entries()?)),
+            tok => return Err(AsmError::new(span, format!("expected an operand, found {}", describe(&tok)))),
+        };
+        Ok((operand, span))
+    }
+
+    /// An operand that must be a literal value
+    fn literal(&mut self) -> Result<Value, AsmError> {
+        match self.operand()? {
+            (Operand::Value(value), _) => Ok(value),
+            (_, span) => Err(AsmError::new(span, "expected a literal value")),
+        }
+    }
+
+    /// Comma separated literals up to the `close` bracket
+    fn items(&mut self, close: char) -> Result<Vec<Value>, AsmError> {
+        let mut items = vec![];
+        self.list(close, |parser| {
+            items.push(parser.literal()?);
+            Ok(())
+        })?;
+        Ok(items)
+    }
+
+    /// `key: value` pairs up to a closing brace
+    fn entries(&mut self) -> Result<Vec<(Value, Value)>, AsmError> {
+        let mut entries: Vec<(Value, Value)> = vec![];
+        self.list('}', |parser| {
+            let span = parser.peek().1;
+            let key = parser.literal()?;
+            match parser.next() {
+                (Token::Colon, _) => (),
+                (tok, span) => return Err(AsmError::new(span, format!("expected ':' after map key, found {}", describe(&tok)))),
+            }
+            if entries.iter().any(|(k, _)| *k == key) {
+                return Err(AsmError::new(span, format!("duplicate map key {}", key)));
+            }
+            entries.push((key, parser.literal()?));
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// Parses comma separated elements with `element` until `close`
+    fn list(&mut self, close: char, mut element: impl FnMut(&mut Parser) -> Result<(), AsmError>) -> Result<(), AsmError> {
+        if self.peek().0 == Token::Close(close) {
+            self.next();
+            return Ok(());
+        }
+        loop {
+            element(self)?;
+            match self.next() {
+                (Token::Comma, _) => continue,
+                (Token::Close(c), _) if c == close => return Ok(()),
+                (tok, span) => return Err(AsmError::new(span, format!("expected ',' or '{}', found {}", close, describe(&tok)))),
+            }
+        }
+    }
+}
+
+fn describe(tok: &Token) -> String {
+    match tok {
+        Token::Ident(name) => format!("'{}'", name),
+        Token::Number(text) => format!("'{}'", text),
+        Token::Char(c) => format!("{:?}", c),
+        Token::Str(s) => format!("{:?}", s),
+        Token::Bytes(bytes) => Value::Bytes(bytes.clone()).to_string(),
+        Token::Colon => String::from("':'"),
+        Token::Comma => String::from("','"),
+        Token::At => String::from("'@'"),
+        Token::Hash => String::from("'#'"),
+        Token::Open(c) | Token::Close(c) => format!("'{}'", c),
+        Token::Newline => String::from("end of line"),
+    }
+}
+
+pub(crate) fn parse_register(name: &str) -> Option<usize> {
+    let digits = name.strip_prefix('r')?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+const SUFFIXES: [&str; 10] = ["i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64", "f32", "f64"];
+
+pub(crate) fn is_float_keyword(name: &str) -> bool {
+    let body = SUFFIXES.iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name);
+    body == "inf" || body == "nan"
+}
+
+/// Parses a numeric literal such as `10`, `-0x1Fi64`, `255u8`, `2.5f32` or
+/// `inf`. Integers default to i32 and floats to f64.
+pub fn parse_number(text: &str, span: Span) -> Result<Value, AsmError> {
+    let invalid = || AsmError::new(span, format!("invalid numeric literal '{}'", text));
+    let clean = text.replace('_', "");
+    let (body, suffix) = match SUFFIXES.iter().find_map(|s| clean.strip_suffix(s).map(|body| (body, *s))) {
+        Some((body, suffix)) if !(body.is_empty() || is_hex(body) && suffix.starts_with('f')) => (body, Some(suffix)),
+        _ => (clean.as_str(), None),
+    };
+    let is_float = !is_hex(body) && (body.contains(['.', 'e', 'E']) || body.ends_with("inf") || body.ends_with("nan"));
+
+    match (suffix, is_float) {
+        (Some("f32"), _) => body.parse().map(Value::F32).map_err(|_| invalid()),
+        (Some("f64"), _) | (None, true) => body.parse().map(Value::F64).map_err(|_| invalid()),
+        (Some(_), true) => Err(AsmError::new(span, format!("integer suffix on floating point literal '{}'", text))),
+        (suffix, false) => {
+            let n = parse_integer(body).ok_or_else(invalid)?;
+            let value = match suffix {
+                Some("i8") => i8::try_from(n).ok().map(Value::I8),
+                Some("i16") => i16::try_from(n).ok().map(Value::I16),
+                Some("i64") => i64::try_from(n).ok().map(Value::I64),
+                Some("u8") => u8::try_from(n).ok().map(Value::U8),
+                Some("u16") => u16::try_from(n).ok().map(Value::U16),
+                Some("u32") => u32::try_from(n).ok().map(Value::U32),
+                Some("u64") => u64::try_from(n).ok().map(Value::U64),
+                _ => i32::try_from(n).ok().map(Value::I32),
+            };
+            value.ok_or_else(|| match suffix {
+                Some(suffix) if suffix != "i32" => AsmError::new(span, format!("literal '{}' does not fit in {}", text, suffix)),
+                _ => AsmError::new(span, format!("literal '{}' does not fit in i32, add an i64 suffix", text)),
+            })
+        }
+    }
+}
+
+fn is_hex(body: &str) -> bool {
+    let digits = body.trim_start_matches(['-', '+']);
+    digits.starts_with("0x") || digits.starts_with("0X")
+}
+
+fn parse_integer(body: &str) -> Option<i128> {
+    let (negative, digits) = match body.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, body.strip_prefix('+').unwrap_or(body)),
+    };
+    let magnitude = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None if digits.chars().all(|c| c.is_ascii_digit()) => digits.parse::<i128>().ok()?,
+        None => return None,
+    };
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// Checks operand counts and kinds for one instruction
+struct Operands<'a> {
+    mnemonic: &'a str,
+    span: Span,
+    items: &'a [(Operand, Span)],
+}
+
+impl<'a> Operands<'a> {
+    fn arity(&self, min: usize, max: usize) -> Result<(), AsmError> {
+        let n = self.items.len();
+        if n >= min && n <= max {
+            return Ok(());
+        }
+        let expected = if min == max { min.to_string() } else { format!("{} to {}", min, max) };
+        let span = self.items.get(max).map(|(_, span)| *span).unwrap_or(self.span);
+        Err(AsmError::new(span, format!("'{}' takes {} operand(s) but {} were given", self.mnemonic, expected, n)))
+    }
+
+    fn value(&self, i: usize) -> Result<Value, AsmError> {
+        match &self.items[i] {
+            (Operand::Value(value), _) => Ok(value.clone()),
+            (_, span) => Err(AsmError::new(*span, format!("'{}' expects a literal value", self.mnemonic))),
+        }
+    }
+
+    fn register(&self, i: usize) -> Result<usize, AsmError> {
+        match &self.items[i] {
+            (Operand::Register(reg), _) if *reg < REGISTER_COUNT => Ok(*reg),
+            (Operand::Register(reg), span) =>
+                Err(AsmError::new(*span, format!("register r{} does not exist, the VM has {} registers", reg, REGISTER_COUNT))),
+            (_, span) => Err(AsmError::new(*span, format!("'{}' expects a register such as r0", self.mnemonic))),
+        }
+    }
+
+    fn count(&self, i: usize) -> Result<usize, AsmError> {
+        let (operand, span) = &self.items[i];
+        let n = match operand {
+            Operand::Value(Value::I32(n)) => usize::try_from(*n).ok(),
+            Operand::Value(Value::I64(n)) => usize::try_from(*n).ok(),
+            _ => None,
+        };
+        n.ok_or_else(|| AsmError::new(*span, format!("'{}' expects a non-negative integer", self.mnemonic)))
+    }
+
+    fn count_or(&self, i: usize, default: usize) -> Result<usize, AsmError> {
+        if i < self.items.len() { self.count(i) } else { Ok(default) }
+    }
+
+    /// A type name such as `i32` or `string`
+    fn value_type(&self, i: usize) -> Result<ValueType, AsmError> {
+        match &self.items[i] {
+            (Operand::Label(name), span) => ValueType::from_name(name)
+                .ok_or_else(|| AsmError::new(*span, format!("unknown type '{}'", name))),
+            (_, span) => Err(AsmError::new(*span, format!("'{}' expects a type name such as i32", self.mnemonic))),
+        }
+    }
+
+    /// A jump target: either a label resolved later or a literal such as `@12`
+    fn target(&self, i: usize) -> Result<(Value, Option<(String, Span)>), AsmError> {
+        match &self.items[i] {
+            (Operand::Label(label), span) => Ok((Value::Address(None), Some((label.clone(), *span)))),
+            (Operand::Value(value @ Value::Address(Some(_))), _) => Ok((value.clone(), None)),
+            (_, span) => Err(AsmError::new(*span, format!("'{}' expects a label or @address", self.mnemonic))),
+        }
+    }
+}
+
+type Target = Option<(String, Span)>;
+
+fn directive(name: &str, span: Span, items: &[(Operand, Span)]) -> Result<MemoryCell, AsmError> {
+    let ops = Operands { mnemonic: name, span, items };
+    match name {
+        ".value" => {
+            ops.arity(1, 1)?;
+            Ok(MemoryCell::Value(ops.value(0)?))
+        },
+        ".tag" => {
+            ops.arity(1, 1)?;
+            match ops.value(0)? {
+                Value::String(tag) => Ok(MemoryCell::MetaData(MetaData::Tag(tag))),
+                _ => Err(AsmError::new(items[0].1, "'.tag' expects a string")),
+            }
+        },
+        ".empty" => {
+            ops.arity(0, 0)?;
+            Ok(MemoryCell::Empty)
+        },
+        _ => Err(AsmError::new(span, format!("unknown directive '{}'", name))),
+    }
+}
+
+/// Generates `instruction`, which parses the operands of every mnemonic,
+/// from the VM's instruction table
+macro_rules! parsers {
+    (|$vm:ident| $( $name:ident = $opcode:literal $mnemonic:literal $( ( $( $arg:ident : $kind:ident ),* ) )? => $body:expr, )*) => {
+        fn instruction(mnemonic: &str, span: Span, items: &[(Operand, Span)]) -> Result<(Instruction, Target), AsmError> {
+            let ops = Operands { mnemonic, span, items };
+            let mut label = None;
+            let inst = match mnemonic {
+                $( $mnemonic => {
+                    ops.arity(0 $( $( + arity!($kind).0 )* )?, 0 $( $( + arity!($kind).1 )* )?)?;
+                    $( parse_operands!(ops, label, 0; $( $arg : $kind ),*); )?
+                    Instruction::$name $( ( $( $arg ),* ) )?
+                } )*
+                _ => return Err(AsmError::new(span, format!("unknown instruction '{}'", mnemonic))),
+            };
+            Ok((inst, label))
+        }
+    };
+}
+
+/// How many operands a kind takes at least and at most. A message is a
+/// value optionally followed by its sender and receiver.
+macro_rules! arity {
+    (argc) => { (0, 1) };
+    (message) => { (1, 3) };
+    ($kind:ident) => { (1, 1) };
+}
+
+/// Binds each operand, counting the operands before it
+macro_rules! parse_operands {
+    ($ops:ident, $label:ident, $i:expr; $arg:ident : $kind:ident $( , $args:ident : $kinds:ident )*) => {
+        let $arg = parse_operands!(@ $kind, $ops, $label, $i);
+        parse_operands!($ops, $label, $i + 1; $( $args : $kinds ),*);
+    };
+    ($ops:ident, $label:ident, $i:expr;) => {};
+    (@ register, $ops:ident, $label:ident, $i:expr) => { $ops.register($i)? };
+    (@ count, $ops:ident, $label:ident, $i:expr) => { $ops.count($i)? };
+    (@ argc, $ops:ident, $label:ident, $i:expr) => { $ops.count_or($i, 0)? };
+    (@ constant, $ops:ident, $label:ident, $i:expr) => { $ops.value($i)? };
+    (@ value_type, $ops:ident, $label:ident, $i:expr) => { $ops.value_type($i)? };
+    (@ target, $ops:ident, $label:ident, $i:expr) => {{
+        let (target, name) = $ops.target($i)?;
+        $label = name;
+        target
+    }};
+    (@ message, $ops:ident, $label:ident, $i:expr) => {
+        Message { from: $ops.count_or($i + 1, 0)?, to: $ops.count_or($i + 2, 0)?, value: $ops.value($i)? }
+    };
+}
+
+instruction_table!(parsers);
+
+
+#[cfg(test)]
+mod tests {
+    use rusty_vm::rvm::vm::MemoryCell;
+
+    use super::*;
+
+    #[test]
+    fn typed_literals_parse() {
+        let span = Span { line: 1, column: 1 };
+        assert_eq!(Value::I32(10), parse_number("10", span).unwrap());
+        assert_eq!(Value::I64(-31), parse_number("-0x1Fi64", span).unwrap());
+        assert_eq!(Value::F32(2.5), parse_number("2.5f32", span).unwrap());
+        assert_eq!(Value::F64(1e100), parse_number("1e100", span).unwrap());
+        assert_eq!(Value::F64(f64::NEG_INFINITY), parse_number("-inf", span).unwrap());
+        assert!(parse_number("3000000000", span).is_err());
+        assert_eq!(Value::U8(255), parse_number("255u8", span).unwrap());
+        assert_eq!(Value::U32(0xFF), parse_number("0xFFu32", span).unwrap());
+        assert_eq!(Value::I16(-300), parse_number("-300i16", span).unwrap());
+        assert_eq!(Value::U64(u64::MAX), parse_number("18_446_744_073_709_551_615u64", span).unwrap());
+        assert_eq!("literal '256u8' does not fit in u8", parse_number("256u8", span).unwrap_err().message);
+        assert!(parse_number("-1u16", span).is_err());
+        assert!(parse_number("1.5u8", span).is_err());
+        assert_eq!(Ok(Value::Bytes(vec![b'o', b'k', 0, 0xFF, b'"'])), parse_value(r#"b"ok\x00\xff\"""#));
+        assert!(parse_value(r#"b"é""#).is_err());
+    }
+
+    #[test]
+    fn compound_literals_parse() {
+        let s = |text: &str| Value::String(String::from(text));
+        assert_eq!(Ok(Value::Array(vec![Value::I32(1), Value::Tuple(vec![s("a"), Value::Bool(true)])])),
+                   parse_value("[1, (\"a\", true)]"));
+        assert_eq!(Ok(Value::Map(vec![(s("k"), Value::Array(vec![])), (Value::I32(2), Value::Address(None))])),
+                   parse_value("{\"k\": [], 2: @null}"));
+
+        let err = parse_value("{1: 2, 1: 3}").unwrap_err();
+        assert_eq!((Span { line: 1, column: 8 }, "duplicate map key 1"), (err.span, err.message.as_str()));
+        assert!(parse_value("[1 2]").is_err());
+        assert!(parse_value("[start]").is_err());
+    }
+
+    #[test]
+    fn assembled_program_runs() {
+        let mut builder = assemble("\
+start:
+    push 10          ; first operand
+    push 20i32
+    add
+    jmp end
+    push 'x'
+end: halt
+").unwrap();
+        builder.start().unwrap();
+        assert_eq!(vec![MemoryCell::Value(Value::I32(30))], builder.results());
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_operand() {
+        let err = assemble("push 1\n  ld r99\n").unwrap_err();
+        assert_eq!(Span { line: 2, column: 6 }, err.span);
+
+        let err = assemble("jz nowhere\n").unwrap_err();
+        assert_eq!("undefined label 'nowhere'", err.message);
+        assert_eq!(Span { line: 1, column: 4 }, err.span);
+
+        for source in ["jmp 5\n", "call @null\n", "jz \"start\"\n"] {
+            let err = assemble(source).unwrap_err();
+            assert_eq!(format!("'{}' expects a label or @address", &source[..source.find(' ').unwrap()]), err.message);
+        }
+        assert!(assemble("jmp @0\n").is_ok());
+    }
+}
@@ -0,0 +1,367 @@
+//! Disassembler producing assembly text that re-assembles to the same image
+
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+};
+
+use rusty_vm::rvm::vm::{instruction_table, Instruction, MemoryCell, MetaData, RustyVM, Value};
+
+use crate::assembler::{is_float_keyword, parse_register};
+
+
+/// Disassembles the whole program in `vm`
+pub fn disassemble(vm: &RustyVM) -> String {
+    let mut text = format!("; {} memory cells\n", vm.memory().len());
+    text.push_str(&disassemble_range(vm, 0..vm.memory().len() + 1));
+    text
+}
+
+/// Disassembles the cells in `range`, one per line with its address in a
+/// trailing comment. Jump targets are named after the VM's symbols where
+/// possible; other targets get local `.L` labels that are not exported
+/// when the text is assembled again.
+pub fn disassemble_range(vm: &RustyVM, range: Range<usize>) -> String {
+    let labels = labels(vm);
+    let mut text = String::new();
+    for address in range {
+        for name in labels.get(&address).into_iter().flatten() {
+            text.push_str(&format!("{}:\n", name));
+        }
+        if let Some(cell) = vm.memory().get(address) {
+            text.push_str(&format!("    {:<40} ; {:04}\n", format_cell(cell, &labels), address));
+        }
+    }
+    text
+}
+
+/// Label names by address: the VM's symbols plus a local label for every
+/// branch target that has no usable symbol
+fn labels(vm: &RustyVM) -> BTreeMap<usize, Vec<String>> {
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for (name, address) in vm.symbols() {
+        if is_label_name(name) {
+            labels.entry(*address).or_default().push(name.clone());
+        }
+    }
+    for cell in vm.memory() {
+        if let MemoryCell::Instruction(inst) = cell {
+            if let Some(Value::Address(Some(target))) = target(inst) {
+                if *target <= vm.memory().len() && !labels.contains_key(target) {
+                    labels.insert(*target, vec![format!(".L{:04}", target)]);
+                }
+            }
+        }
+    }
+    labels
+}
+
+/// True if `name` reads back as a label rather than another operand
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_ident = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_' || c == '.');
+    starts_ident
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        && !matches!(name, "true" | "false")
+        && !is_float_keyword(name)
+        && parse_register(name).is_none()
+}
+
+fn target(inst: &Instruction) -> Option<&Value> {
+    match inst {
+        Instruction::Jmp(target)
+        | Instruction::Jz(target)
+        | Instruction::Jnz(target)
+        | Instruction::Jneg(target)
+        | Instruction::Jnneg(target)
+        | Instruction::Jpos(target)
+        | Instruction::Jnpos(target)
+        | Instruction::Jeq(target)
+        | Instruction::Jne(target)
+        | Instruction::Jlt(target)
+        | Instruction::Jnlt(target)
+        | Instruction::Jgt(target)
+        | Instruction::Jngt(target)
+        | Instruction::Call(target, _) => Some(target),
+        _ => None,
+    }
+}
+
+fn format_cell(cell: &MemoryCell, labels: &BTreeMap<usize, Vec<String>>) -> String {
+    match cell {
+        MemoryCell::Instruction(inst) => format_instruction(inst, labels),
+        MemoryCell::Value(value) => format!(".value {}", format_value(value)),
+        MemoryCell::MetaData(MetaData::Tag(tag)) => format!(".tag {:?}", tag),
+        MemoryCell::Empty => String::from(".empty"),
+    }
+}
+
+fn format_target(target: &Value, labels: &BTreeMap<usize, Vec<String>>) -> String {
+    match target {
+        Value::Address(Some(address)) => match labels.get(address) {
+            Some(names) => names[0].clone(),
+            None => format_value(target),
+        },
+        _ => format_value(target),
+    }
+}
+
+/// Generates `format_instruction` from the VM's instruction table
+macro_rules! formatters {
+    (|$vm:ident| $( $name:ident = $opcode:literal $mnemonic:literal $( ( $( $arg:ident : $kind:ident ),* ) )? => $body:expr, )*) => {
+        /// Formats an instruction using the assembler's mnemonics
+        pub fn format_instruction(inst: &Instruction, labels: &BTreeMap<usize, Vec<String>>) -> String {
+            let (mnemonic, operands): (&str, Vec<String>) = match inst {
+                $( Instruction::$name $( ( $( $arg ),* ) )? => ($mnemonic, vec![$( $( format_operand!($kind, $arg, labels) ),* )?]), )*
+            };
+            if operands.is_empty() {
+                mnemonic.to_string()
+            } else {
+                format!("{} {}", mnemonic, operands.join(", "))
+            }
+        }
+    };
+}
+
+macro_rules! format_operand {
+    (register, $arg:ident, $labels:ident) => { format!("r{}", $arg) };
+    (constant, $arg:ident, $labels:ident) => { format_value($arg) };
+    (target, $arg:ident, $labels:ident) => { format_target($arg, $labels) };
+    // the sender and receiver are left out when both are zero, as the assembler allows
+    (message, $arg:ident, $labels:ident) => {
+        if $arg.from == 0 && $arg.to == 0 {
+            format_value(&$arg.value)
+        } else {
+            format!("{}, {}, {}", format_value(&$arg.value), $arg.from, $arg.to)
+        }
+    };
+    // counts and types print as they are written
+    ($kind:ident, $arg:ident, $labels:ident) => { $arg.to_string() };
+}
+
+instruction_table!(formatters);
+
+/// Formats a value as an assembler literal
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::I32(n) => n.to_string(),
+        Value::I64(n) => format!("{}i64", n),
+        Value::I8(n) => format!("{}i8", n),
+        Value::I16(n) => format!("{}i16", n),
+        Value::U8(n) => format!("{}u8", n),
+        Value::U16(n) => format!("{}u16", n),
+        Value::U32(n) => format!("{}u32", n),
+        Value::U64(n) => format!("{}u64", n),
+        Value::F32(n) => format!("{}f32", format_float(*n as f64, n.is_nan(), || format!("{:?}", n))),
+        Value::F64(n) => format_float(*n, n.is_nan(), || format!("{:?}", n)),
+        Value::Char(c) => format!("{:?}", c),
+        Value::String(s) => format!("{:?}", s),
+        Value::Bytes(_) => value.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Symbol(inner) => format!("#{}", format_value(inner)),
+        Value::Address(Some(address)) => format!("@{}", address),
+        Value::Address(None) => String::from("@null"),
+        Value::Array(items) => format!("[{}]", format_items(items)),
+        Value::Tuple(items) => format!("({})", format_items(items)),
+        Value::Map(entries) => {
+            let entries: Vec<String> = entries.iter()
+                .map(|(key, value)| format!("{}: {}", format_value(key), format_value(value)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        },
+    }
+}
+
+fn format_items(items: &[Value]) -> String {
+    items.iter().map(format_value).collect::<Vec<_>>().join(", ")
+}
+
+/// Debug formatting is the shortest text that parses back to the same
+/// float; only the non-finite spellings differ from the assembler's
+fn format_float(n: f64, nan: bool, debug: impl Fn() -> String) -> String {
+    if nan {
+        String::from("nan")
+    } else if n == f64::INFINITY {
+        String::from("inf")
+    } else if n == f64::NEG_INFINITY {
+        String::from("-inf")
+    } else {
+        debug()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rusty_vm::rvm::vm::*;
+
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn disassembly_reassembles_to_the_same_image() {
+        let source = r#"
+Start:
+    push 10
+    push -3000000000i64
+    push 2.5f32
+    push 1e100
+    push -inff32
+    push nan
+    push '\n'
+    push "tab\there \"quoted\""
+    push #"sym"
+    push @null
+    ld_imm r15, true
+    add_r r1, r2, r3
+    mov r0, r1
+    jz .skip
+    call Helper, 2
+    out 0, 27.56
+    out 1, 'x', 3, 4
+    out_top 2
+    print
+    in 2
+    try_in 3
+    alloc 4
+    store
+    load
+    free
+    gc
+    push [1, "two", [3.0f32], ()]
+    push ('x', #true)
+    push {"k": {}, 2: @null}
+    new_array 2
+    new_tuple 0
+    new_map 1
+    index
+    set_index
+    len
+    append
+    map_get
+    map_set
+    concat
+    substr
+    str_len
+    char_at
+    str_cmp
+    to_upper
+    to_lower
+    split
+    find
+    format 2
+    conv string
+    conv f32
+    type_of
+    and
+    or
+    xor
+    not
+    shl
+    shr
+    sar
+    rotl
+    rotr
+    land
+    lor
+    lnot
+    dup
+    dup2
+    swap
+    over
+    rot
+    pick 3
+    roll 2
+    depth
+    clear
+    mod
+    rem
+    neg
+    abs
+    min
+    max
+    pow
+    sqrt
+    sin
+    cos
+    tan
+    exp
+    ln
+    floor
+    ceil
+    round
+    is_nan
+    push 255u8
+    push -128i8
+    push 0xFFFFu16
+    push -300i16
+    push 7u32
+    push 18446744073709551615u64
+    push b"bin\x00\xfe\"\\"
+    conv bytes
+.skip:
+    jmp Start
+Helper:
+    ld_local 1
+    ret
+    .value 42i64
+    .tag "meta"
+    .empty
+End:
+"#;
+        let original = assemble(source).unwrap();
+        let text = disassemble(original.vm());
+        let reassembled = assemble(&text).unwrap_or_else(|err| panic!("{}\n{}", err, text));
+
+        assert_eq!(original.vm().save_image().unwrap(), reassembled.vm().save_image().unwrap());
+        assert!(text.contains("jz .L0097"));
+    }
+
+    /// One of every instruction in the VM's table, with sample operands
+    macro_rules! samples {
+        (|$vm:ident| $( $name:ident = $opcode:literal $mnemonic:literal $( ( $( $arg:ident : $kind:ident ),* ) )? => $body:expr, )*) => {
+            vec![$( Instruction::$name $( ( $( sample!($kind) ),* ) )?, )*]
+        };
+    }
+
+    macro_rules! sample {
+        (register) => { 3 };
+        (count) => { 2 };
+        (argc) => { 1 };
+        (constant) => { Value::Tuple(vec![Value::U16(7), Value::String(String::from("s"))]) };
+        (target) => { Value::Address(Some(0)) };
+        (message) => { Message { from: 4, to: 5, value: Value::Char('x') } };
+        (value_type) => { rusty_vm::rvm::types::ValueType::F32 };
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        let mut vm = RustyVM::new();
+        for inst in instruction_table!(samples) {
+            vm.push(MemoryCell::Instruction(inst));
+        }
+
+        let text = disassemble(&vm);
+        let reassembled = assemble(&text).unwrap_or_else(|err| panic!("{}\n{}", err, text));
+        assert_eq!(vm.memory(), reassembled.vm().memory());
+        let loaded = RustyVM::load_image(&vm.save_image().unwrap()).unwrap();
+        assert_eq!(vm.memory(), loaded.memory());
+    }
+
+    #[test]
+    fn unnamed_targets_get_local_labels() {
+        let mut vm = RustyVM::new();
+        vm.push(MemoryCell::Instruction(Instruction::Jmp(Value::Address(Some(2)))));
+        vm.push(MemoryCell::Instruction(Instruction::Nop));
+        vm.push(MemoryCell::Instruction(Instruction::Halt));
+
+        let text = disassemble_range(&vm, 0..3);
+        assert_eq!("    jmp .L0002                               ; 0000\n\
+                    \x20   nop                                      ; 0001\n\
+                    .L0002:\n\
+                    \x20   halt                                     ; 0002\n", text);
+
+        let reassembled = assemble(&text).unwrap();
+        assert_eq!(vm.save_image().unwrap(), reassembled.vm().save_image().unwrap());
+    }
+}
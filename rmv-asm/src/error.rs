@@ -0,0 +1,38 @@
+//! Assembler diagnostics
+
+use std::fmt;
+
+use crate::lexer::Span;
+
+
+/// An error located at a line and column of the assembly source
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl AsmError {
+    pub fn new(span: Span, message: impl Into<String>) -> AsmError {
+        AsmError {
+            span,
+            message: message.into()
+        }
+    }
+
+    /// Formats the error compiler style, quoting the offending source line
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let line = source.lines().nth(self.span.line - 1).unwrap_or("");
+        let caret = " ".repeat(self.span.column.saturating_sub(1));
+        format!("{}:{}:{}: error: {}\n    {}\n    {}^",
+                file, self.span.line, self.span.column, self.message, line, caret)
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
@@ -0,0 +1,273 @@
+//! Tokenizer for Rusty VM assembly
+
+use crate::error::AsmError;
+
+
+/// Line and column (both 1 based) of a token in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),      // mnemonics, labels, registers, directives and keywords
+    Number(String),     // numeric literal including sign and type suffix
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),     // b"..." byte string
+    Colon,
+    Comma,
+    At,
+    Hash,
+    Open(char),         // one of ( [ {
+    Close(char),        // one of ) ] }
+    Newline,
+}
+
+/// Splits assembly source into tokens. Comments run from `;` to the end of the line.
+pub fn tokenize(source: &str) -> Result<Vec<(Token, Span)>, AsmError> {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        pos: 0,
+        line: 1,
+        column: 1,
+    };
+    let mut tokens = vec![];
+
+    while let Some(c) = lexer.peek() {
+        let span = lexer.span();
+        match c {
+            '\n' => {
+                lexer.bump();
+                tokens.push((Token::Newline, span));
+            },
+            c if c.is_whitespace() => {
+                lexer.bump();
+            },
+            ';' => {
+                while !matches!(lexer.peek(), Some('\n') | None) {
+                    lexer.bump();
+                }
+            },
+            ':' => {
+                lexer.bump();
+                tokens.push((Token::Colon, span));
+            },
+            ',' => {
+                lexer.bump();
+                tokens.push((Token::Comma, span));
+            },
+            '@' => {
+                lexer.bump();
+                tokens.push((Token::At, span));
+            },
+            '#' => {
+                lexer.bump();
+                tokens.push((Token::Hash, span));
+            },
+            '(' | '[' | '{' => {
+                lexer.bump();
+                tokens.push((Token::Open(c), span));
+            },
+            ')' | ']' | '}' => {
+                lexer.bump();
+                tokens.push((Token::Close(c), span));
+            },
+            '\'' => {
+                lexer.bump();
+                let c = lexer.char_body('\'', span)?;
+                if lexer.bump() != Some('\'') {
+                    return Err(AsmError::new(span, "character literal must contain exactly one character"));
+                }
+                tokens.push((Token::Char(c), span));
+            },
+            '"' => {
+                lexer.bump();
+                let mut text = String::new();
+                loop {
+                    match lexer.peek() {
+                        Some('"') => {
+                            lexer.bump();
+                            break;
+                        },
+                        Some('\n') | None => return Err(AsmError::new(span, "unterminated string literal")),
+                        Some(_) => text.push(lexer.char_body('"', span)?),
+                    }
+                }
+                tokens.push((Token::Str(text), span));
+            },
+            c if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' && lexer.peek_at(1).is_some_and(|n| n.is_ascii_digit()) => {
+                let mut text = String::new();
+                text.push(c);
+                lexer.bump();
+                while let Some(c) = lexer.peek() {
+                    // an exponent may carry its own sign
+                    let exponent_sign = (c == '-' || c == '+') && text.ends_with(['e', 'E']) && !text.contains("0x");
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '_' || exponent_sign {
+                        text.push(c);
+                        lexer.bump();
+                    } else {
+                        break;
+                    }
+                }
+                if text == "-" || text == "+" {
+                    return Err(AsmError::new(span, format!("unexpected character '{}'", text)));
+                }
+                tokens.push((Token::Number(text), span));
+            },
+            'b' if lexer.peek_at(1) == Some('"') => {
+                lexer.bump();
+                lexer.bump();
+                let mut bytes = vec![];
+                loop {
+                    match lexer.peek() {
+                        Some('"') => {
+                            lexer.bump();
+                            break;
+                        },
+                        Some('\n') | None => return Err(AsmError::new(span, "unterminated byte string literal")),
+                        Some(_) => bytes.push(lexer.byte_body(span)?),
+                    }
+                }
+                tokens.push((Token::Bytes(bytes), span));
+            },
+            c if c.is_alphabetic() || c == '_' || c == '.' => {
+                let mut text = String::new();
+                while let Some(c) = lexer.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '.' {
+                        text.push(c);
+                        lexer.bump();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push((Token::Ident(text), span));
+            },
+            c => return Err(AsmError::new(span, format!("unexpected character '{}'", c))),
+        }
+    }
+    tokens.push((Token::Newline, lexer.span()));
+    Ok(tokens)
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer {
+    fn span(&self) -> Span {
+        Span { line: self.line, column: self.column }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    /// Reads one possibly escaped character of a char or string literal
+    fn char_body(&mut self, quote: char, start: Span) -> Result<char, AsmError> {
+        let span = self.span();
+        match self.bump() {
+            Some('\\') => match self.bump() {
+                Some('n') => Ok('\n'),
+                Some('r') => Ok('\r'),
+                Some('t') => Ok('\t'),
+                Some('0') => Ok('\0'),
+                Some('\\') => Ok('\\'),
+                Some('\'') => Ok('\''),
+                Some('"') => Ok('"'),
+                Some('u') => self.unicode_escape(span),
+                Some(c) => Err(AsmError::new(span, format!("unknown escape sequence '\\{}'", c))),
+                None => Err(AsmError::new(start, "unterminated literal")),
+            },
+            Some('\n') | None => Err(AsmError::new(start, "unterminated literal")),
+            Some(c) if c == quote => Err(AsmError::new(span, "empty character literal")),
+            Some(c) => Ok(c),
+        }
+    }
+
+    /// One byte of a byte string: an ASCII character, an escape or `\xNN`
+    fn byte_body(&mut self, start: Span) -> Result<u8, AsmError> {
+        let span = self.span();
+        if self.peek() == Some('\\') && self.peek_at(1) == Some('x') {
+            self.bump();
+            self.bump();
+            let digits: String = (0..2).filter_map(|_| self.bump()).collect();
+            if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(AsmError::new(span, "expected two hex digits after \\x"));
+            }
+            return Ok(u8::from_str_radix(&digits, 16).unwrap_or_default());
+        }
+        let c = self.char_body('"', start)?;
+        u8::try_from(c)
+            .ok()
+            .filter(u8::is_ascii)
+            .ok_or_else(|| AsmError::new(span, format!("non-ASCII character {:?} in byte string, use \\x escapes", c)))
+    }
+
+    /// Parses the `{XXXX}` part of a `\u{XXXX}` escape
+    fn unicode_escape(&mut self, span: Span) -> Result<char, AsmError> {
+        if self.bump() != Some('{') {
+            return Err(AsmError::new(span, "expected '{' after \\u"));
+        }
+        let mut digits = String::new();
+        loop {
+            match self.bump() {
+                Some('}') => break,
+                Some(c) if c.is_ascii_hexdigit() => digits.push(c),
+                _ => return Err(AsmError::new(span, "malformed unicode escape")),
+            }
+        }
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| AsmError::new(span, format!("invalid unicode scalar value '{}'", digits)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_carry_line_and_column() {
+        let tokens = tokenize("start:\n    push -2.5f64 ; comment\n").unwrap();
+        let expected = vec![
+            (Token::Ident(String::from("start")), Span { line: 1, column: 1 }),
+            (Token::Colon, Span { line: 1, column: 6 }),
+            (Token::Newline, Span { line: 1, column: 7 }),
+            (Token::Ident(String::from("push")), Span { line: 2, column: 5 }),
+            (Token::Number(String::from("-2.5f64")), Span { line: 2, column: 10 }),
+            (Token::Newline, Span { line: 2, column: 27 }),
+            (Token::Newline, Span { line: 3, column: 1 }),
+        ];
+        assert_eq!(expected, tokens);
+    }
+
+    #[test]
+    fn unterminated_string_is_reported() {
+        let err = tokenize("out 0, \"oops\n").unwrap_err();
+        assert_eq!(Span { line: 1, column: 8 }, err.span);
+    }
+}
@@ -0,0 +1,10 @@
+//! Assembler and disassembler for the Rusty VM
+
+pub mod assembler;
+pub mod disassembler;
+pub mod error;
+pub mod lexer;
+
+pub use assembler::{assemble, parse, parse_value};
+pub use disassembler::{disassemble, disassemble_range};
+pub use error::AsmError;
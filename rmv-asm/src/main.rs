@@ -1,73 +1,80 @@
+use std::{env, fs, process};
 
-#[derive(Debug, Clone)]
-enum Token {
-    Num(usize),
-    Symbol(String),
-    Push,
-    Add,
-    Dump,
-    Halt,
-    Empty,
-}
+use rmv_asm::{assemble, disassemble};
+use rusty_vm::rvm::vm::RustyVM;
 
 
-fn main()  {
-    println!("Rusty VM Assembler");
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} <source.rasm> [-o <image.rvmi>]", program);
+    eprintln!("       {} -d <image.rvmi>", program);
+    eprintln!("Runs the program, or writes a binary image when -o is given");
+    eprintln!("With -d, prints the image as assembly source");
+    process::exit(2);
+}
 
-    let test = "\
-start:
-    push 10     ; this is a comment
-    push 20     ; this is also a comment
-    add         ; add the two values
-    dump        ; dump the VM state
-    halt        ; halt the VM
-    ";
+fn main()  {
+    let args: Vec<String> = env::args().collect();
+    let (path, output) = match args.as_slice() {
+        [_, flag, image] if flag == "-d" => disassemble_image(image),
+        [_, path] => (path, None),
+        [_, path, flag, output] if flag == "-o" => (path, Some(output)),
+        _ => usage(&args[0]),
+    };
 
-    let lexer = regex_lexer::LexerBuilder::new()
-    .token(r";[\w\s]+\n", |_| None)
-    .token(r"[a-z]+:", |l| Some(Token::Symbol(l.to_string())))
-    .token(r"[0-9]+", |num| Some(Token::Num(num.parse().unwrap())))
-    .token(r"add", |_| Some(Token::Add))
-    .token(r"push", |_| Some(Token::Push))
-    .token(r"dump", |_| Some(Token::Dump))
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    };
 
-    .token(r"halt", |_| Some(Token::Halt))
-    .token(r"\s+", |_| None) // skip whitespace
-    // ...
-    .build();
+    let mut program = match assemble(&source) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("{}", err.render(path, &source));
+            process::exit(1);
+        }
+    };
 
-    match lexer {
-        Ok(l) => {
-            let tokens = l.tokens(test);
-            let mut tokvec: Vec<Token> = vec![];
-            for tok in tokens {
-                tokvec.push(tok.clone());
+    if let Some(output) = output {
+        let image = match program.vm().save_image() {
+            Ok(image) => image,
+            Err(err) => {
+                eprintln!("{}: {}", output, err);
+                process::exit(1);
             }
-            let len = tokvec.len();
+        };
+        if let Err(err) = fs::write(output, image) {
+            eprintln!("{}: {}", output, err);
+            process::exit(1);
+        }
+        return;
+    }
 
-            for mut i in 1..len {
-                let mut lah = Token::Empty;
-                if i + 1 < len {
-                    lah = tokvec[i + 1].clone();
-                } else {
-                    lah = Token::Empty;
-                }
+    program.standard_devices();
+    if let Err(err) = program.start() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
 
-                match &tokvec[i] {
-                    Token::Push => match lah {
-                        Token::Num(n) => {
-                            println!("push {}", n);
-                            i += 2;
-                        },
-                        _ => {}
-                    },
-                    //t => println!("{:#?}", t),
-                    _ => {}
-                }
-            }
+fn disassemble_image(path: &str) -> ! {
+    let image = match fs::read(path) {
+        Ok(image) => image,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    };
+    match RustyVM::load_image(&image) {
+        Ok(vm) => {
+            print!("{}", disassemble(&vm));
+            process::exit(0);
         },
-        Err(msg) => {
-            println!("Error: {}", msg)
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
         }
     }
 }
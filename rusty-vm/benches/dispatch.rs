@@ -0,0 +1,106 @@
+//! Compares `RustyVM::run` with the lowered dispatch loop of `run_lowered`.
+//!
+//! Run with `cargo bench -p rusty-vm`. Each workload is built fresh for
+//! every sample and the fastest of `SAMPLES` runs is reported.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use rusty_vm::rvm::{
+    builder::VMBuilder,
+    error::{HaltReason, VmError},
+    vm::Value,
+};
+
+const ITERATIONS: i64 = 200_000;
+const SAMPLES: usize = 10;
+
+/// Emits a program into a builder, which then gets a halt appended
+type Workload = fn(&mut VMBuilder);
+
+/// Sums a counter into a register
+fn register_loop(b: &mut VMBuilder) {
+    b.ld_imm(0, Value::I64(0))
+        .ld_imm(1, Value::I64(ITERATIONS))
+        .ld_imm(2, Value::I64(-1))
+        .label("Loop")
+        .add_r(0, 0, 1)
+        .add_r(1, 1, 2)
+        .jnz("Loop");
+}
+
+/// Counts down on the stack
+fn stack_loop(b: &mut VMBuilder) {
+    b.push(Value::I64(ITERATIONS))
+        .label("Loop")
+        .push(Value::I64(1))
+        .sub()
+        .dup()
+        .push(Value::I64(0))
+        .cmp()
+        .jne("Loop")
+        .pop();
+}
+
+/// Pushes and drops a string constant, which `run` clones twice per fetch
+fn string_constants(b: &mut VMBuilder) {
+    b.ld_imm(1, Value::I64(ITERATIONS))
+        .ld_imm(2, Value::I64(-1))
+        .label("Loop")
+        .push(Value::String("a constant long enough to need a heap allocation".to_string()))
+        .pop()
+        .add_r(1, 1, 2)
+        .jnz("Loop");
+}
+
+/// Calls a subroutine that doubles its argument
+fn calls(b: &mut VMBuilder) {
+    b.ld_imm(1, Value::I64(ITERATIONS))
+        .ld_imm(2, Value::I64(-1))
+        .label("Loop")
+        .push(Value::I64(21))
+        .call("Twice", 1)
+        .pop()
+        .add_r(1, 1, 2)
+        .jnz("Loop")
+        .halt()
+        .label("Twice")
+        .ld_local(0)
+        .dup()
+        .add()
+        .ret();
+}
+
+fn build(workload: Workload) -> VMBuilder {
+    let mut builder = VMBuilder::new();
+    workload(&mut builder);
+    builder.halt().build().unwrap();
+    builder
+}
+
+fn fastest(workload: Workload, start: fn(&mut VMBuilder) -> Result<HaltReason, VmError>) -> Duration {
+    (0..SAMPLES).map(|_| {
+        let mut builder = build(workload);
+        let time = Instant::now();
+        black_box(start(&mut builder).unwrap());
+        time.elapsed()
+    }).min().unwrap()
+}
+
+fn main() {
+    let workloads: [(&str, Workload); 4] = [
+        ("register loop", register_loop),
+        ("stack loop", stack_loop),
+        ("string constants", string_constants),
+        ("calls", calls),
+    ];
+    println!("{:<18} {:>14} {:>14} {:>8}", "workload", "run", "run_lowered", "speedup");
+    for (name, workload) in workloads {
+        let interpreted = fastest(workload, VMBuilder::start);
+        let lowered = fastest(workload, VMBuilder::start_lowered);
+        println!("{:<18} {:>14?} {:>14?} {:>7.2}x",
+                 name, interpreted, lowered, interpreted.as_secs_f64() / lowered.as_secs_f64());
+    }
+}
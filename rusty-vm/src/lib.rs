@@ -1,2 +1 @@
-#[macro_use] extern crate text_io;
 pub mod rvm;
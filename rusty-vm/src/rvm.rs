@@ -1,2 +1,10 @@
 pub mod vm;
-pub mod builder;
\ No newline at end of file
+pub mod builder;
+pub mod error;
+pub mod image;
+pub mod heap;
+pub mod arith;
+pub mod lowered;
+pub mod types;
+pub mod port;
+pub mod devices;
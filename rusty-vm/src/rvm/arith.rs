@@ -0,0 +1,489 @@
+//! Numeric promotion and the operators shared by the arithmetic instructions
+//!
+//! Every numeric instruction goes through `binary`, `unary` or `shift`: operands
+//! are first promoted to a common type, then the operator is applied by the
+//! integer or float table for that type. The numeric types and the widening
+//! rules between them are listed once, in the `numbers!` table at the end.
+
+use super::vm::{ArithmeticMode, Value};
+
+
+/// Operators taking two numeric operands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,        // euclidean remainder
+    Rem,        // remainder with the sign of the dividend
+    Min,
+    Max,
+    Pow,
+    And,        // bitwise operators are defined for integers only
+    Or,
+    Xor,
+}
+
+impl BinaryOp {
+    /// Instruction name, used in error messages
+    pub fn name(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "Add",
+            BinaryOp::Sub => "Sub",
+            BinaryOp::Mul => "Mul",
+            BinaryOp::Div => "Div",
+            BinaryOp::Mod => "Mod",
+            BinaryOp::Rem => "Rem",
+            BinaryOp::Min => "Min",
+            BinaryOp::Max => "Max",
+            BinaryOp::Pow => "Pow",
+            BinaryOp::And => "And",
+            BinaryOp::Or => "Or",
+            BinaryOp::Xor => "Xor",
+        }
+    }
+
+    /// What the operands must be, used in error messages
+    pub fn operands(self) -> &'static str {
+        match self {
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Xor => "integers",
+            _ => "numbers",
+        }
+    }
+}
+
+/// Operators taking one numeric operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Abs,
+    Not,        // bitwise complement, for integers only
+}
+
+impl UnaryOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "Neg",
+            UnaryOp::Abs => "Abs",
+            UnaryOp::Not => "Not",
+        }
+    }
+
+    /// What the operand must be, used in error messages
+    pub fn operand(self) -> &'static str {
+        match self {
+            UnaryOp::Not => "an integer",
+            _ => "a number",
+        }
+    }
+}
+
+/// Shifts and rotations, which take an integer and a bit count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftOp {
+    Shl,
+    Shr,        // logical, filling with zeros
+    Sar,        // arithmetic for signed types, logical for unsigned ones
+    Rotl,
+    Rotr,
+}
+
+impl ShiftOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            ShiftOp::Shl => "Shl",
+            ShiftOp::Shr => "Shr",
+            ShiftOp::Sar => "Sar",
+            ShiftOp::Rotl => "Rotl",
+            ShiftOp::Rotr => "Rotr",
+        }
+    }
+}
+
+/// Why an operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithError {
+    Mismatch,           // no common type that the operator is defined for
+    DivideByZero,       // integer division or remainder by zero
+    Overflow,           // the exact result does not fit and the mode is checked
+    NegativeExponent,   // an integer power with a negative exponent
+}
+
+/// Result of an operation along with the flags it raised
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome<T = Value> {
+    pub value: T,
+    pub overflow: bool,     // the exact result did not fit
+    pub carry: bool,        // unsigned carry or borrow out of an add or sub
+}
+
+impl<T> Outcome<T> {
+    fn map(self, f: fn(T) -> Value) -> Outcome {
+        Outcome { value: f(self.value), overflow: self.overflow, carry: self.carry }
+    }
+}
+
+/// Generates the promotion and dispatch of every numeric type from its
+/// table. A type is listed with its `Value` variant, Rust type and kind:
+/// `signed` along with its unsigned twin, `unsigned` or `float`. A widening
+/// rule lets operands of the two types meet in the wider one, in either
+/// order; `From` between the Rust types keeps every rule lossless.
+macro_rules! numbers {
+    (
+        types { $( $variant:ident($t:ty): $kind:ident $( ($unsigned:ty) )? ),* $(,)? }
+        widen { $( $from:ident => $to:ident ),* $(,)? }
+    ) => {
+        /// Operands after promotion to a common type
+        enum Operands {
+            $( $variant($t, $t), )*
+        }
+
+        /// Brings two values to a common type
+        fn promote(left: &Value, right: &Value) -> Option<Operands> {
+            match (left, right) {
+                $( (Value::$variant(l), Value::$variant(r)) => Some(Operands::$variant(*l, *r)), )*
+                $(
+                    (Value::$from(l), Value::$to(r)) => Some(Operands::$to((*l).into(), *r)),
+                    (Value::$to(l), Value::$from(r)) => Some(Operands::$to(*l, (*r).into())),
+                )*
+                _ => None,
+            }
+        }
+
+        pub fn binary(op: BinaryOp, mode: ArithmeticMode, left: &Value, right: &Value) -> Result<Outcome, ArithError> {
+            match promote(left, right).ok_or(ArithError::Mismatch)? {
+                $( Operands::$variant(l, r) => operate!($kind, binary, $variant, $t, op, mode, l, r), )*
+            }
+        }
+
+        pub fn unary(op: UnaryOp, mode: ArithmeticMode, value: &Value) -> Result<Outcome, ArithError> {
+            match *value {
+                $( Value::$variant(n) => operate!($kind, unary, $variant, $t, op, mode, n), )*
+                _ => Err(ArithError::Mismatch),
+            }
+        }
+
+        /// Shifts by `count` bits, which must be less than the width of the
+        /// integer. Rotations take any count, wrapping it around the width.
+        pub fn shift(op: ShiftOp, value: &Value, count: i128) -> Result<Value, ArithError> {
+            match *value {
+                $( Value::$variant(n) => operate!($kind, shift, $variant, $t, op, n, count), )*
+                _ => Err(ArithError::Mismatch),
+            }
+        }
+
+        $( number!($kind $( ($unsigned) )?, $t); )*
+    };
+}
+
+/// Applies an operator through the integer or float table of a type
+macro_rules! operate {
+    (float, binary, $variant:ident, $t:ty, $op:ident, $mode:ident, $l:ident, $r:ident) => {
+        <$t as Float>::binary($op, $l, $r).map(|n| float(Value::$variant(n)))
+    };
+    (float, unary, $variant:ident, $t:ty, $op:ident, $mode:ident, $n:ident) => {
+        <$t as Float>::unary($op, $n).map(|n| float(Value::$variant(n)))
+    };
+    // floats have no bits to shift
+    (float, shift, $variant:ident, $t:ty, $op:ident, $n:ident, $count:ident) => {
+        { let _ = $n; Err(ArithError::Mismatch) }
+    };
+    ($integer:ident, binary, $variant:ident, $t:ty, $op:ident, $mode:ident, $l:ident, $r:ident) => {
+        integer_binary($op, $mode, $l, $r).map(|o| o.map(Value::$variant))
+    };
+    ($integer:ident, unary, $variant:ident, $t:ty, $op:ident, $mode:ident, $n:ident) => {
+        integer_unary($op, $mode, $n).map(|o| o.map(Value::$variant))
+    };
+    ($integer:ident, shift, $variant:ident, $t:ty, $op:ident, $n:ident, $count:ident) => {
+        integer_shift($op, $n, $count).map(Value::$variant)
+    };
+}
+
+fn float(value: Value) -> Outcome {
+    Outcome { value, overflow: false, carry: false }
+}
+
+/// Exact, wrapped and saturated results of an integer operator
+type Results<T> = (Option<T>, T, T);
+
+/// Picks the result `mode` asks for
+fn choose<T>(mode: ArithmeticMode, (exact, wrapped, saturated): Results<T>, carry: bool) -> Result<Outcome<T>, ArithError> {
+    let overflow = exact.is_none();
+    let value = match mode {
+        ArithmeticMode::Checked => exact.ok_or(ArithError::Overflow)?,
+        ArithmeticMode::Wrapping => wrapped,
+        ArithmeticMode::Saturating => saturated,
+    };
+    Ok(Outcome { value, overflow, carry })
+}
+
+fn integer_binary<T: Integer + Copy>(op: BinaryOp, mode: ArithmeticMode, l: T, r: T) -> Result<Outcome<T>, ArithError> {
+    choose(mode, T::binary(op, l, r)?, T::carry(op, l, r))
+}
+
+fn integer_unary<T: Integer>(op: UnaryOp, mode: ArithmeticMode, n: T) -> Result<Outcome<T>, ArithError> {
+    choose(mode, T::unary(op, n), false)
+}
+
+fn integer_shift<T: Integer>(op: ShiftOp, n: T, count: i128) -> Result<T, ArithError> {
+    let bits = T::BITS as i128;
+    let count = match op {
+        ShiftOp::Rotl | ShiftOp::Rotr => count.rem_euclid(bits),
+        _ if (0..bits).contains(&count) => count,
+        _ => return Err(ArithError::Overflow),
+    };
+    Ok(T::shift(op, n, count as u32))
+}
+
+trait Integer: Sized {
+    const BITS: u32;
+    fn binary(op: BinaryOp, l: Self, r: Self) -> Result<Results<Self>, ArithError>;
+    fn carry(op: BinaryOp, l: Self, r: Self) -> bool;
+    fn unary(op: UnaryOp, n: Self) -> Results<Self>;
+    fn shift(op: ShiftOp, n: Self, count: u32) -> Self;
+}
+
+trait Float: Sized {
+    fn binary(op: BinaryOp, l: Self, r: Self) -> Result<Self, ArithError>;
+    fn unary(op: UnaryOp, n: Self) -> Result<Self, ArithError>;
+}
+
+/// The operators every integer type shares, given the type and its
+/// unsigned twin
+macro_rules! integer_ops {
+    ($t:ty, $unsigned:ty) => {
+            const BITS: u32 = <$t>::BITS;
+
+            fn binary(op: BinaryOp, l: $t, r: $t) -> Result<Results<$t>, ArithError> {
+                let exact = |n: $t| (Some(n), n, n);
+                Ok(match op {
+                    BinaryOp::Add => (l.checked_add(r), l.wrapping_add(r), l.saturating_add(r)),
+                    BinaryOp::Sub => (l.checked_sub(r), l.wrapping_sub(r), l.saturating_sub(r)),
+                    BinaryOp::Mul => (l.checked_mul(r), l.wrapping_mul(r), l.saturating_mul(r)),
+                    BinaryOp::Div | BinaryOp::Mod | BinaryOp::Rem if r == 0 => return Err(ArithError::DivideByZero),
+                    BinaryOp::Div => (l.checked_div(r), l.wrapping_div(r), l.saturating_div(r)),
+                    // the only overflowing case, MIN by -1, is exactly 0 so wrapping is always right
+                    BinaryOp::Mod => exact(l.wrapping_rem_euclid(r)),
+                    BinaryOp::Rem => exact(l.wrapping_rem(r)),
+                    BinaryOp::Min => exact(l.min(r)),
+                    BinaryOp::Max => exact(l.max(r)),
+                    BinaryOp::Pow => {
+                        let mut exp = exponent(r as i128)?;
+                        if let Ok(exp) = u32::try_from(exp) {
+                            return Ok((l.checked_pow(exp), l.wrapping_pow(exp), l.saturating_pow(exp)));
+                        }
+                        // the std pows take u32 exponents, so square by hand beyond that
+                        let (mut exact, mut wrapped, mut saturated) = (Some(1 as $t), 1 as $t, 1 as $t);
+                        let (mut square_exact, mut square_wrapped, mut square_saturated) = (Some(l), l, l);
+                        while exp > 0 {
+                            if exp & 1 == 1 {
+                                exact = exact.zip(square_exact).and_then(|(n, m)| n.checked_mul(m));
+                                wrapped = wrapped.wrapping_mul(square_wrapped);
+                                saturated = saturated.saturating_mul(square_saturated);
+                            }
+                            exp >>= 1;
+                            square_exact = square_exact.and_then(|m| m.checked_mul(m));
+                            square_wrapped = square_wrapped.wrapping_mul(square_wrapped);
+                            square_saturated = square_saturated.saturating_mul(square_saturated);
+                        }
+                        (exact, wrapped, saturated)
+                    },
+                    BinaryOp::And => exact(l & r),
+                    BinaryOp::Or => exact(l | r),
+                    BinaryOp::Xor => exact(l ^ r),
+                })
+            }
+
+            fn carry(op: BinaryOp, l: $t, r: $t) -> bool {
+                match op {
+                    BinaryOp::Add => (l as $unsigned).overflowing_add(r as $unsigned).1,
+                    BinaryOp::Sub => (l as $unsigned).overflowing_sub(r as $unsigned).1,
+                    _ => false,
+                }
+            }
+
+            fn shift(op: ShiftOp, n: $t, count: u32) -> $t {
+                match op {
+                    ShiftOp::Shl => n << count,
+                    ShiftOp::Shr => ((n as $unsigned) >> count) as $t,
+                    ShiftOp::Sar => n >> count,
+                    ShiftOp::Rotl => n.rotate_left(count),
+                    ShiftOp::Rotr => n.rotate_right(count),
+                }
+            }
+    };
+}
+
+/// Implements the integer or float table of a type
+macro_rules! number {
+    (signed($unsigned:ty), $t:ty) => {
+        impl Integer for $t {
+            integer_ops!($t, $unsigned);
+
+            fn unary(op: UnaryOp, n: $t) -> Results<$t> {
+                match op {
+                    UnaryOp::Neg => (n.checked_neg(), n.wrapping_neg(), n.saturating_neg()),
+                    UnaryOp::Abs => (n.checked_abs(), n.wrapping_abs(), n.saturating_abs()),
+                    UnaryOp::Not => (Some(!n), !n, !n),
+                }
+            }
+        }
+    };
+    (unsigned, $t:ty) => {
+        impl Integer for $t {
+            integer_ops!($t, $t);
+
+            fn unary(op: UnaryOp, n: $t) -> Results<$t> {
+                match op {
+                    // only zero has an unsigned negation; anything else saturates to zero
+                    UnaryOp::Neg => (n.checked_neg(), n.wrapping_neg(), 0),
+                    UnaryOp::Abs => (Some(n), n, n),
+                    UnaryOp::Not => (Some(!n), !n, !n),
+                }
+            }
+        }
+    };
+    (float, $t:ty) => {
+        impl Float for $t {
+            fn binary(op: BinaryOp, l: $t, r: $t) -> Result<$t, ArithError> {
+                Ok(match op {
+                    BinaryOp::Add => l + r,
+                    BinaryOp::Sub => l - r,
+                    BinaryOp::Mul => l * r,
+                    BinaryOp::Div => l / r,
+                    BinaryOp::Mod => l.rem_euclid(r),
+                    BinaryOp::Rem => l % r,
+                    BinaryOp::Min => l.min(r),
+                    BinaryOp::Max => l.max(r),
+                    BinaryOp::Pow => l.powf(r),
+                    BinaryOp::And | BinaryOp::Or | BinaryOp::Xor => return Err(ArithError::Mismatch),
+                })
+            }
+
+            fn unary(op: UnaryOp, n: $t) -> Result<$t, ArithError> {
+                match op {
+                    UnaryOp::Neg => Ok(-n),
+                    UnaryOp::Abs => Ok(n.abs()),
+                    UnaryOp::Not => Err(ArithError::Mismatch),
+                }
+            }
+        }
+    };
+}
+
+numbers! {
+    types {
+        I8(i8): signed(u8),
+        I16(i16): signed(u16),
+        I32(i32): signed(u32),
+        I64(i64): signed(u64),
+        U8(u8): unsigned,
+        U16(u16): unsigned,
+        U32(u32): unsigned,
+        U64(u64): unsigned,
+        F32(f32): float,
+        F64(f64): float,
+    }
+    widen {
+        I8 => I16, I8 => I32, I8 => I64, I16 => I32, I16 => I64, I32 => I64,
+        U8 => U16, U8 => U32, U8 => U64, U16 => U32, U16 => U64, U32 => U64,
+        U8 => I16, U8 => I32, U8 => I64, U16 => I32, U16 => I64, U32 => I64,
+        F32 => F64,
+    }
+}
+
+fn exponent(exp: i128) -> Result<u64, ArithError> {
+    match u64::try_from(exp) {
+        Ok(exp) => Ok(exp),
+        Err(_) if exp < 0 => Err(ArithError::NegativeExponent),
+        Err(_) => Err(ArithError::Overflow),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_pick_the_integer_result() {
+        let add = |mode| binary(BinaryOp::Add, mode, &Value::I32(i32::MAX), &Value::I32(1));
+        assert_eq!(Err(ArithError::Overflow), add(ArithmeticMode::Checked));
+        assert_eq!(Ok(Outcome { value: Value::I32(i32::MIN), overflow: true, carry: false }), add(ArithmeticMode::Wrapping));
+        assert_eq!(Ok(Outcome { value: Value::I32(i32::MAX), overflow: true, carry: false }), add(ArithmeticMode::Saturating));
+
+        let sub = binary(BinaryOp::Sub, ArithmeticMode::Checked, &Value::I64(1), &Value::I64(2)).unwrap();
+        assert_eq!(Value::I64(-1), sub.value);
+        assert!(sub.carry && !sub.overflow);
+    }
+
+    #[test]
+    fn operands_are_checked_per_operator() {
+        let checked = ArithmeticMode::Checked;
+        assert_eq!(Err(ArithError::Mismatch), binary(BinaryOp::Mul, checked, &Value::I32(1), &Value::U32(1)));
+        assert_eq!(Err(ArithError::Mismatch), binary(BinaryOp::Xor, checked, &Value::F64(1.0), &Value::F64(1.0)));
+        assert_eq!(Err(ArithError::DivideByZero), binary(BinaryOp::Rem, checked, &Value::I64(1), &Value::I64(0)));
+        assert_eq!(Err(ArithError::NegativeExponent), binary(BinaryOp::Pow, checked, &Value::I32(2), &Value::I32(-1)));
+        assert_eq!(Err(ArithError::Overflow), unary(UnaryOp::Abs, checked, &Value::I64(i64::MIN)));
+        assert_eq!(Value::F32(0.5), binary(BinaryOp::Div, checked, &Value::F32(1.0), &Value::F32(2.0)).unwrap().value);
+    }
+
+    #[test]
+    fn unsigned_and_narrow_types_follow_their_width() {
+        let checked = ArithmeticMode::Checked;
+        let sub = binary(BinaryOp::Sub, ArithmeticMode::Wrapping, &Value::U8(1), &Value::U8(2)).unwrap();
+        assert_eq!(Outcome { value: Value::U8(255), overflow: true, carry: true }, sub);
+        assert_eq!(Err(ArithError::Overflow), binary(BinaryOp::Mul, checked, &Value::I16(300), &Value::I16(300)));
+        assert_eq!(Value::U32(0), unary(UnaryOp::Neg, ArithmeticMode::Saturating, &Value::U32(5)).unwrap().value);
+        assert_eq!(Value::I8(i8::MAX), unary(UnaryOp::Abs, ArithmeticMode::Saturating, &Value::I8(i8::MIN)).unwrap().value);
+        assert_eq!(Ok(Value::U8(0x7F)), shift(ShiftOp::Sar, &Value::U8(0xFE), 1));
+        assert_eq!(Ok(Value::I8(-1)), shift(ShiftOp::Sar, &Value::I8(-2), 1));
+        assert_eq!(Ok(Value::U16(0x8000)), shift(ShiftOp::Rotr, &Value::U16(1), 17));
+        assert_eq!(Err(ArithError::Overflow), shift(ShiftOp::Shl, &Value::U64(1), 64));
+    }
+
+    #[test]
+    fn narrower_operands_widen() {
+        let checked = ArithmeticMode::Checked;
+        let mul = |l: &Value, r: &Value| binary(BinaryOp::Mul, checked, l, r).map(|o| o.value);
+        assert_eq!(Ok(Value::I64(3 * i32::MAX as i64)), mul(&Value::I32(i32::MAX), &Value::I64(3)));
+        assert_eq!(Ok(Value::I64(-6)), mul(&Value::I64(3), &Value::I32(-2)));
+        assert_eq!(Ok(Value::I16(510)), mul(&Value::U8(255), &Value::I16(2)));
+        assert_eq!(Ok(Value::U64(6)), mul(&Value::U16(2), &Value::U64(3)));
+        assert_eq!(Ok(Value::F64(0.75)), mul(&Value::F32(0.5), &Value::F64(1.5)));
+        // no type holds every value of both, so these stay a mismatch
+        assert_eq!(Err(ArithError::Mismatch), mul(&Value::U64(1), &Value::I64(1)));
+        assert_eq!(Err(ArithError::Mismatch), mul(&Value::I8(1), &Value::U8(1)));
+        assert_eq!(Err(ArithError::Mismatch), mul(&Value::I32(1), &Value::F64(1.0)));
+        // the wider type still overflows by its own rules
+        assert_eq!(Err(ArithError::Overflow), binary(BinaryOp::Add, checked, &Value::I8(1), &Value::I16(i16::MAX)).map(|o| o.value));
+    }
+
+    #[test]
+    fn exponents_beyond_u32_follow_the_mode() {
+        let pow = |mode, base, exp| binary(BinaryOp::Pow, mode, &Value::I64(base), &Value::I64(exp)).map(|o| o.value);
+        let (checked, wrapping, saturating) = (ArithmeticMode::Checked, ArithmeticMode::Wrapping, ArithmeticMode::Saturating);
+        let big = 1 << 32;
+        assert_eq!(Ok(Value::I64(1)), pow(checked, 1, big));
+        assert_eq!(Ok(Value::I64(0)), pow(checked, 0, big));
+        assert_eq!(Ok(Value::I64(1)), pow(checked, -1, big));
+        assert_eq!(Ok(Value::I64(-1)), pow(checked, -1, big + 1));
+        assert_eq!(Err(ArithError::Overflow), pow(checked, 2, big));
+        assert_eq!(Ok(Value::I64(0)), pow(wrapping, 2, big));
+        assert_eq!(Ok(Value::I64(i64::MAX)), pow(saturating, -2, big));
+        assert_eq!(Ok(Value::I64(i64::MIN)), pow(saturating, -2, big + 1));
+
+        // 3^(2^32) is 3 squared 32 times
+        let mut expected = 3i64;
+        for _ in 0..32 {
+            expected = expected.wrapping_mul(expected);
+        }
+        assert_eq!(Ok(Value::I64(expected)), pow(wrapping, 3, big));
+        // 3^(2^64 - 1) wraps to the inverse of 3, as 3^(2^64) wraps to 1
+        match binary(BinaryOp::Pow, wrapping, &Value::U64(3), &Value::U64(u64::MAX)).unwrap().value {
+            Value::U64(n) => assert_eq!(1, n.wrapping_mul(3)),
+            value => panic!("expected a u64, got {:?}", value),
+        }
+    }
+}
@@ -3,60 +3,98 @@
 
 use std::{
     collections::{
-        HashMap
+        HashMap,
+        HashSet
     },
 };
 
-use super::vm::{RustyVM, MemoryCell, Instruction, Value, Message};
+use super::vm::{RustyVM, MemoryCell, Instruction, Value, Message, ArithmeticMode};
+use super::types::ValueType;
+use super::error::{BuildError, Fault, HaltReason, VmError};
+use super::lowered::Program;
+use super::port::Port;
+use super::devices::{self, Clock, Console, Random};
 
 
+#[derive(Debug)]
 pub struct VMBuilder {
     vm: RustyVM, 
     pc: usize,
     symbol_table: HashMap<String, Value>,
-    unresolved_label_refs: HashMap<String, usize>,    
+    unresolved_label_refs: Vec<(String, usize)>,
+    local_labels: HashSet<String>,
     built: bool
 }
 
+impl Default for VMBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VMBuilder {
     pub fn new() -> VMBuilder {
         VMBuilder {
             vm: RustyVM::new(),
             pc: 0,
             symbol_table: HashMap::new(),
-            unresolved_label_refs: HashMap::new(),
+            unresolved_label_refs: vec![],
+            local_labels: HashSet::new(),
             built: false
         }
     }
 
-    pub fn start(&mut self) {
+    pub fn start(&mut self) -> Result<HaltReason, VmError> {
+        if ! self.built {
+            return Err(VmError::NotBuilt(Fault { pc: 0, instruction: None }));
+        }
+        self.vm.reset();
+        self.vm.run()
+    }
+
+    /// Lowers the built program for `RustyVM::run_lowered`
+    pub fn lower(&self) -> Result<Program, VmError> {
         if ! self.built {
-            println!("You must call build before start");
-            return
+            return Err(VmError::NotBuilt(Fault { pc: 0, instruction: None }));
         }
+        Ok(self.vm.lower())
+    }
+
+    /// Like `start`, but runs the program lowered
+    pub fn start_lowered(&mut self) -> Result<HaltReason, VmError> {
+        let program = self.lower()?;
         self.vm.reset();
-        self.vm.run();
+        self.vm.run_lowered(&program)
     }
 
-    pub fn build(&mut self) -> &mut Self {
+    pub fn build(&mut self) -> Result<&mut Self, BuildError> {
         // reconciles all labels here
         for (label, address) in &self.unresolved_label_refs {
             if let Some(Value::Address(Some(actual_address))) = self.symbol_table.get(label) {
                 match self.vm.get_instruction(*address) {
-                    MemoryCell::Instruction(Instruction::Jmp(_)) => {
-                        self.vm.set_instruction(MemoryCell::Instruction(Instruction::Jmp(Value::Address(Some(*actual_address))) ), *address)
+                    MemoryCell::Instruction(inst) => match inst.with_target(*actual_address) {
+                        Some(resolved) => self.vm.set_instruction(MemoryCell::Instruction(resolved), *address),
+                        None => return Err(BuildError::InvalidInstruction(*address))
                     },
 
-                    _ => println!("Invalid instruction at {}", &address)
+                    _ => return Err(BuildError::InvalidInstruction(*address))
 
                 }
             } else {
-                println!("Invalid instruction at {}", &address);
+                return Err(BuildError::UnresolvedLabel(label.clone(), *address));
+            }
+        }
+
+        for (label, value) in &self.symbol_table {
+            if let Value::Address(Some(address)) = value {
+                if !self.local_labels.contains(label) {
+                    self.vm.add_symbol(label, *address);
+                }
             }
         }
 
         self.built = true;
-        self
+        Ok(self)
     }
 
     pub fn label(&mut self, label: &str) -> &mut Self {
@@ -64,98 +102,1597 @@ impl VMBuilder {
         self
     }
 
+    /// Defines a label that can be jumped to but is not exported as a VM symbol
+    pub fn local_label(&mut self, label: &str) -> &mut Self {
+        self.local_labels.insert(String::from(label));
+        self.label(label)
+    }
+
     pub fn push(&mut self, val: Value) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Push(val)));
-        self.pc += 1;
-        self
+        self.instruction(Instruction::Push(val))
+    }
+
+    pub fn pop(&mut self) -> &mut Self {
+        self.instruction(Instruction::Pop)
+    }
+
+    pub fn dup(&mut self) -> &mut Self {
+        self.instruction(Instruction::Dup)
+    }
+
+    pub fn dup2(&mut self) -> &mut Self {
+        self.instruction(Instruction::Dup2)
+    }
+
+    pub fn swap(&mut self) -> &mut Self {
+        self.instruction(Instruction::Swap)
+    }
+
+    pub fn over(&mut self) -> &mut Self {
+        self.instruction(Instruction::Over)
+    }
+
+    pub fn rot(&mut self) -> &mut Self {
+        self.instruction(Instruction::Rot)
+    }
+
+    /// Pushes a copy of the nth value below the top of the stack
+    pub fn pick(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::Pick(n))
+    }
+
+    /// Moves the nth value below the top of the stack to the top
+    pub fn roll(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::Roll(n))
+    }
+
+    pub fn depth(&mut self) -> &mut Self {
+        self.instruction(Instruction::Depth)
+    }
+
+    pub fn clear(&mut self) -> &mut Self {
+        self.instruction(Instruction::Clear)
     }
 
     pub fn add(&mut self) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Add));
-        self.pc += 1;
-        self
+        self.instruction(Instruction::Add)
     }
 
     pub fn sub(&mut self) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Sub));
-        self.pc += 1;
-        self
+        self.instruction(Instruction::Sub)
     }
 
     pub fn mul(&mut self) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Mul));
-        self.pc += 1;
-        self
+        self.instruction(Instruction::Mul)
     }
 
     pub fn div(&mut self) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Div));
-        self.pc += 1;
+        self.instruction(Instruction::Div)
+    }
+
+    pub fn add_wrap(&mut self) -> &mut Self {
+        self.instruction(Instruction::AddWrap)
+    }
+
+    pub fn sub_wrap(&mut self) -> &mut Self {
+        self.instruction(Instruction::SubWrap)
+    }
+
+    pub fn mul_wrap(&mut self) -> &mut Self {
+        self.instruction(Instruction::MulWrap)
+    }
+
+    pub fn div_wrap(&mut self) -> &mut Self {
+        self.instruction(Instruction::DivWrap)
+    }
+
+    pub fn add_sat(&mut self) -> &mut Self {
+        self.instruction(Instruction::AddSat)
+    }
+
+    pub fn sub_sat(&mut self) -> &mut Self {
+        self.instruction(Instruction::SubSat)
+    }
+
+    pub fn mul_sat(&mut self) -> &mut Self {
+        self.instruction(Instruction::MulSat)
+    }
+
+    pub fn div_sat(&mut self) -> &mut Self {
+        self.instruction(Instruction::DivSat)
+    }
+
+    pub fn arithmetic_mode(&mut self, mode: ArithmeticMode) -> &mut Self {
+        self.vm.set_arithmetic_mode(mode);
         self
     }
 
+    pub fn add_r(&mut self, dst: usize, a: usize, b: usize) -> &mut Self {
+        self.instruction(Instruction::AddR(dst, a, b))
+    }
+
+    pub fn sub_r(&mut self, dst: usize, a: usize, b: usize) -> &mut Self {
+        self.instruction(Instruction::SubR(dst, a, b))
+    }
+
+    pub fn mul_r(&mut self, dst: usize, a: usize, b: usize) -> &mut Self {
+        self.instruction(Instruction::MulR(dst, a, b))
+    }
+
+    pub fn div_r(&mut self, dst: usize, a: usize, b: usize) -> &mut Self {
+        self.instruction(Instruction::DivR(dst, a, b))
+    }
+
+    pub fn ld(&mut self, reg: usize) -> &mut Self {
+        self.instruction(Instruction::Ld(reg))
+    }
+
+    pub fn st(&mut self, reg: usize) -> &mut Self {
+        self.instruction(Instruction::St(reg))
+    }
+
+    pub fn mov(&mut self, dst: usize, src: usize) -> &mut Self {
+        self.instruction(Instruction::Mov(dst, src))
+    }
+
+    pub fn ld_imm(&mut self, reg: usize, val: Value) -> &mut Self {
+        self.instruction(Instruction::LdImm(reg, val))
+    }
+
+    pub fn cmp(&mut self) -> &mut Self {
+        self.instruction(Instruction::Cmp)
+    }
+
+    pub fn cmp_keep(&mut self) -> &mut Self {
+        self.instruction(Instruction::CmpKeep)
+    }
+
     pub fn jump(&mut self, label: &str) -> &mut Self {
-        match self.symbol_table.get(label) {
-            Some(Value::Address(Some(v))) => {
-                self.pc += 1;
-                self.vm.push(MemoryCell::Instruction(Instruction::Jmp(Value::Address(Some(*v)))));
-            },
-            _ => { 
-                self.vm.push(MemoryCell::Instruction(Instruction::Jmp(Value::Address(None))));
-                self.unresolved_label_refs.insert(label.to_string(), self.pc);
-                self.pc += 1;
-             },
-        }
-        self
+        self.branch(label, Instruction::Jmp)
     }
 
-    pub fn out(&mut self, port: usize, message: Message) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Out(port, message)));
-        self.pc += 1;
-        self
+    pub fn jz(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jz)
     }
 
-    pub fn halt(&mut self) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Halt));
-        self.pc += 1;
+    pub fn jnz(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jnz)
+    }
+
+    pub fn jneg(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jneg)
+    }
+
+    pub fn jnneg(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jnneg)
+    }
+
+    pub fn jpos(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jpos)
+    }
+
+    pub fn jnpos(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jnpos)
+    }
+
+    pub fn jeq(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jeq)
+    }
+
+    pub fn jne(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jne)
+    }
+
+    pub fn jlt(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jlt)
+    }
+
+    pub fn jnlt(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jnlt)
+    }
+
+    pub fn jgt(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jgt)
+    }
+
+    pub fn jngt(&mut self, label: &str) -> &mut Self {
+        self.branch(label, Instruction::Jngt)
+    }
+
+    pub fn call(&mut self, label: &str, argc: usize) -> &mut Self {
+        self.branch(label, |target| Instruction::Call(target, argc))
+    }
+
+    pub fn ret(&mut self) -> &mut Self {
+        self.instruction(Instruction::Ret)
+    }
+
+    pub fn ld_local(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::LdLocal(n))
+    }
+
+    pub fn st_local(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::StLocal(n))
+    }
+
+    /// Allocates `n` heap cells and pushes the address of the first
+    pub fn alloc(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::Alloc(n))
+    }
+
+    /// Pops an address and frees the heap block starting there
+    pub fn free(&mut self) -> &mut Self {
+        self.instruction(Instruction::Free)
+    }
+
+    /// Pops an address and pushes the heap cell it points at
+    pub fn load(&mut self) -> &mut Self {
+        self.instruction(Instruction::Load)
+    }
+
+    /// Pops a value and then an address, and writes the value to the heap
+    pub fn store(&mut self) -> &mut Self {
+        self.instruction(Instruction::Store)
+    }
+
+    /// Runs the garbage collector
+    pub fn gc(&mut self) -> &mut Self {
+        self.instruction(Instruction::Gc)
+    }
+
+    /// Enables automatic garbage collection every `cells` allocated heap cells
+    pub fn gc_threshold(&mut self, cells: usize) -> &mut Self {
+        self.vm.set_gc_threshold(Some(cells));
         self
     }
 
-    pub fn dump(&mut self) -> &mut Self {
-        self.vm.push(MemoryCell::Instruction(Instruction::Dump));
-        self.pc += 1;
+    /// Limits the heap of the VM being built to `cells` cells
+    pub fn max_heap_cells(&mut self, cells: usize) -> &mut Self {
+        self.vm.set_max_heap_cells(cells);
         self
     }
 
-    pub fn results(&self) -> Vec<MemoryCell> {
-        self.vm.get_stack()
+    /// Pops `n` values into an array
+    pub fn new_array(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::NewArray(n))
     }
 
-}
+    /// Pops `n` values into a tuple
+    pub fn new_tuple(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::NewTuple(n))
+    }
 
+    /// Pops `n` key/value pairs into a map
+    pub fn new_map(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::NewMap(n))
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::rvm::{builder, vm::*};
+    pub fn index(&mut self) -> &mut Self {
+        self.instruction(Instruction::Index)
+    }
 
-    #[test]
-    fn builder_creates_vm() {
-        let mut builder = builder::VMBuilder::new();
-        builder
-            .push(Value::I32(10))
-            .halt()
-            .build()
-            .start();
-        
-        let stack = builder.results();
-        for mem in stack {
-            match mem {
-                MemoryCell::Value(Value::I32(n)) => {
-                    assert_eq!(10, n);
-                },
-                _ => panic!("Stack does not contain pushed value")
-            }            
+    pub fn set_index(&mut self) -> &mut Self {
+        self.instruction(Instruction::SetIndex)
+    }
+
+    pub fn len(&mut self) -> &mut Self {
+        self.instruction(Instruction::Len)
+    }
+
+    pub fn append(&mut self) -> &mut Self {
+        self.instruction(Instruction::Append)
+    }
+
+    pub fn map_get(&mut self) -> &mut Self {
+        self.instruction(Instruction::MapGet)
+    }
+
+    pub fn map_set(&mut self) -> &mut Self {
+        self.instruction(Instruction::MapSet)
+    }
+
+    /// Pops two strings or chars, or two bytes, and pushes them joined
+    pub fn concat(&mut self) -> &mut Self {
+        self.instruction(Instruction::Concat)
+    }
+
+    /// Pops a length, a start index and a string, and pushes the substring.
+    /// Indexes count chars, not bytes.
+    pub fn substr(&mut self) -> &mut Self {
+        self.instruction(Instruction::Substr)
+    }
+
+    pub fn str_len(&mut self) -> &mut Self {
+        self.instruction(Instruction::StrLen)
+    }
+
+    pub fn char_at(&mut self) -> &mut Self {
+        self.instruction(Instruction::CharAt)
+    }
+
+    pub fn str_cmp(&mut self) -> &mut Self {
+        self.instruction(Instruction::StrCmp)
+    }
+
+    pub fn to_upper(&mut self) -> &mut Self {
+        self.instruction(Instruction::ToUpper)
+    }
+
+    pub fn to_lower(&mut self) -> &mut Self {
+        self.instruction(Instruction::ToLower)
+    }
+
+    pub fn split(&mut self) -> &mut Self {
+        self.instruction(Instruction::Split)
+    }
+
+    pub fn find(&mut self) -> &mut Self {
+        self.instruction(Instruction::Find)
+    }
+
+    /// Pops `n` arguments and a format string, and pushes the string with
+    /// each `{}` replaced by the next argument
+    pub fn format(&mut self, n: usize) -> &mut Self {
+        self.instruction(Instruction::Format(n))
+    }
+
+    /// Euclidean remainder, which is never negative
+    pub fn modulo(&mut self) -> &mut Self {
+        self.instruction(Instruction::Mod)
+    }
+
+    /// Remainder with the sign of the dividend
+    pub fn rem(&mut self) -> &mut Self {
+        self.instruction(Instruction::Rem)
+    }
+
+    pub fn neg(&mut self) -> &mut Self {
+        self.instruction(Instruction::Neg)
+    }
+
+    pub fn abs(&mut self) -> &mut Self {
+        self.instruction(Instruction::Abs)
+    }
+
+    pub fn min(&mut self) -> &mut Self {
+        self.instruction(Instruction::Min)
+    }
+
+    pub fn max(&mut self) -> &mut Self {
+        self.instruction(Instruction::Max)
+    }
+
+    /// Pops an exponent and a base and pushes the power
+    pub fn pow(&mut self) -> &mut Self {
+        self.instruction(Instruction::Pow)
+    }
+
+    /// Float functions set the non_finite flag when the result is NaN or infinite
+    pub fn sqrt(&mut self) -> &mut Self {
+        self.instruction(Instruction::Sqrt)
+    }
+
+    pub fn sin(&mut self) -> &mut Self {
+        self.instruction(Instruction::Sin)
+    }
+
+    pub fn cos(&mut self) -> &mut Self {
+        self.instruction(Instruction::Cos)
+    }
+
+    pub fn tan(&mut self) -> &mut Self {
+        self.instruction(Instruction::Tan)
+    }
+
+    pub fn exp(&mut self) -> &mut Self {
+        self.instruction(Instruction::Exp)
+    }
+
+    pub fn ln(&mut self) -> &mut Self {
+        self.instruction(Instruction::Ln)
+    }
+
+    pub fn floor(&mut self) -> &mut Self {
+        self.instruction(Instruction::Floor)
+    }
+
+    pub fn ceil(&mut self) -> &mut Self {
+        self.instruction(Instruction::Ceil)
+    }
+
+    /// Rounds half away from zero, leaving integers unchanged
+    pub fn round(&mut self) -> &mut Self {
+        self.instruction(Instruction::Round)
+    }
+
+    pub fn is_nan(&mut self) -> &mut Self {
+        self.instruction(Instruction::IsNaN)
+    }
+
+    /// Pops a value and pushes it converted to `target`
+    pub fn conv(&mut self, target: ValueType) -> &mut Self {
+        self.instruction(Instruction::Conv(target))
+    }
+
+    /// Pops a value and pushes a symbol naming its type
+    pub fn type_of(&mut self) -> &mut Self {
+        self.instruction(Instruction::TypeOf)
+    }
+
+    /// Bitwise and of two integers
+    pub fn and(&mut self) -> &mut Self {
+        self.instruction(Instruction::And)
+    }
+
+    pub fn or(&mut self) -> &mut Self {
+        self.instruction(Instruction::Or)
+    }
+
+    pub fn xor(&mut self) -> &mut Self {
+        self.instruction(Instruction::Xor)
+    }
+
+    /// Bitwise complement of an integer
+    pub fn not(&mut self) -> &mut Self {
+        self.instruction(Instruction::Not)
+    }
+
+    /// Pops a bit count and an integer and pushes the integer shifted left
+    pub fn shl(&mut self) -> &mut Self {
+        self.instruction(Instruction::Shl)
+    }
+
+    /// Logical shift right, filling with zeros
+    pub fn shr(&mut self) -> &mut Self {
+        self.instruction(Instruction::Shr)
+    }
+
+    /// Shift right, arithmetic for signed types, logical for unsigned ones
+    pub fn sar(&mut self) -> &mut Self {
+        self.instruction(Instruction::Sar)
+    }
+
+    /// Pops a bit count and an integer and pushes the integer rotated left
+    pub fn rotl(&mut self) -> &mut Self {
+        self.instruction(Instruction::Rotl)
+    }
+
+    pub fn rotr(&mut self) -> &mut Self {
+        self.instruction(Instruction::Rotr)
+    }
+
+    /// Logical and of two bools
+    pub fn land(&mut self) -> &mut Self {
+        self.instruction(Instruction::LAnd)
+    }
+
+    pub fn lor(&mut self) -> &mut Self {
+        self.instruction(Instruction::LOr)
+    }
+
+    pub fn lnot(&mut self) -> &mut Self {
+        self.instruction(Instruction::LNot)
+    }
+
+    pub fn max_call_depth(&mut self, depth: usize) -> &mut Self {
+        self.vm.set_max_call_depth(depth);
+        self
+    }
+
+    /// Emits a branch instruction to `label`; labels that are not yet
+    /// defined are recorded and patched when `build` is called
+    fn branch(&mut self, label: &str, make: impl Fn(Value) -> Instruction) -> &mut Self {
+        match self.symbol_table.get(label) {
+            Some(Value::Address(Some(v))) => self.instruction(make(Value::Address(Some(*v)))),
+            _ => self.instruction_to(make(Value::Address(None)), label),
         }
     }
 
+    /// Emits any instruction as-is; used by tools such as the assembler
+    pub fn instruction(&mut self, inst: Instruction) -> &mut Self {
+        self.cell(MemoryCell::Instruction(inst))
+    }
+
+    /// Places a raw memory cell, such as a value or metadata, in the program
+    pub fn cell(&mut self, cell: MemoryCell) -> &mut Self {
+        self.vm.push(cell);
+        self.pc += 1;
+        self
+    }
+
+    /// Emits a branch instruction whose target is patched to `label` by `build`
+    pub fn instruction_to(&mut self, inst: Instruction, label: &str) -> &mut Self {
+        self.unresolved_label_refs.push((label.to_string(), self.pc));
+        self.instruction(inst)
+    }
+
+    pub fn out(&mut self, port: usize, message: Message) -> &mut Self {
+        self.instruction(Instruction::Out(port, message))
+    }
+
+    /// Pops the top of the stack and sends it on `port`
+    pub fn out_top(&mut self, port: usize) -> &mut Self {
+        self.instruction(Instruction::OutTop(port))
+    }
+
+    /// Pops the top of the stack and sends it as text on port 0
+    pub fn print(&mut self) -> &mut Self {
+        self.instruction(Instruction::Print)
+    }
+
+    /// Sets the VM id sent as `from` by `out_top` and `print`
+    pub fn id(&mut self, id: usize) -> &mut Self {
+        self.vm.set_id(id);
+        self
+    }
+
+    /// Pushes the value of the next message received on `port`
+    pub fn input(&mut self, port: usize) -> &mut Self {
+        self.instruction(Instruction::In(port))
+    }
+
+    /// Like `input`, but sets the zero flag and pushes nothing when no message is available
+    pub fn try_input(&mut self, port: usize) -> &mut Self {
+        self.instruction(Instruction::TryIn(port))
+    }
+
+    /// Makes `input` suspend the VM rather than fail when no message is available
+    pub fn blocking_input(&mut self, blocking: bool) -> &mut Self {
+        self.vm.set_blocking_input(blocking);
+        self
+    }
+
+    /// Attaches `device` to `port` of the VM being built
+    pub fn port(&mut self, port: usize, device: impl Port + 'static) -> &mut Self {
+        self.vm.attach(port, device);
+        self
+    }
+
+    /// Attaches the console, error console, clock and a time seeded random
+    /// generator to their ports in `devices`
+    pub fn standard_devices(&mut self) -> &mut Self {
+        self.port(devices::CONSOLE_PORT, Console::stdout())
+            .port(devices::ERROR_PORT, Console::stderr())
+            .port(devices::CLOCK_PORT, Clock::new())
+            .port(devices::RANDOM_PORT, Random::from_time())
+    }
+
+    pub fn halt(&mut self) -> &mut Self {
+        self.instruction(Instruction::Halt)
+    }
+
+    pub fn dump(&mut self) -> &mut Self {
+        self.instruction(Instruction::Dump)
+    }
+
+    pub fn vm(&self) -> &RustyVM {
+        &self.vm
+    }
+
+    /// Gives the host access to the VM, e.g. to feed input and resume it with `run`
+    pub fn vm_mut(&mut self) -> &mut RustyVM {
+        &mut self.vm
+    }
+
+    pub fn results(&self) -> Vec<MemoryCell> {
+        self.vm.get_stack()
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::rvm::{builder::{self, VMBuilder}, error::*, port::*, types::ValueType, vm::*};
+
+    /// Builds a program, halts it at its end and returns the error it raises
+    fn run_err(build: impl FnOnce(&mut VMBuilder)) -> VmError {
+        let mut builder = VMBuilder::new();
+        build(&mut builder);
+        builder.halt().build().unwrap().start().unwrap_err()
+    }
+
+    #[test]
+    fn builder_creates_vm() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(10))
+            .halt()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        
+        let stack = builder.results();
+        for mem in stack {
+            match mem {
+                MemoryCell::Value(Value::I32(n)) => {
+                    assert_eq!(10, n);
+                },
+                _ => panic!("Stack does not contain pushed value")
+            }            
+        }
+    }
+
+    #[test]
+    fn conditional_branch_follows_flags() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(5))
+            .push(Value::I32(5))
+            .sub()
+            .jz("Zero")
+            .push(Value::I32(1))
+            .label("Zero")
+            .push(Value::I32(2))
+            .sub()
+            .jnneg("Done")
+            .push(Value::I32(3))
+            .label("Done")
+            .halt()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        let stack: Vec<i32> = builder.results().into_iter().map(|mem| match mem {
+            MemoryCell::Value(Value::I32(n)) => n,
+            _ => panic!("Unexpected stack contents")
+        }).collect();
+        assert_eq!(vec![-2, 3], stack);
+    }
+
+    #[test]
+    fn cmp_sets_compare_flags() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(3))
+            .push(Value::F64(3.5))
+            .cmp()
+            .jnlt("Fail")
+            .push(Value::String(String::from("abc")))
+            .push(Value::String(String::from("abc")))
+            .cmp_keep()
+            .jne("Fail")
+            .push(Value::F32(f32::NAN))
+            .push(Value::F32(1.0))
+            .cmp()
+            .jeq("Fail")
+            .jlt("Fail")
+            .jgt("Fail")
+            .halt()
+            .label("Fail")
+            .push(Value::Bool(false))
+            .halt()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        assert_eq!(2, builder.results().len());
+
+        let run = |l: i32, r: i32, keep: bool| {
+            let mut builder = builder::VMBuilder::new();
+            builder.push(Value::I32(l)).push(Value::I32(r));
+            if keep { builder.cmp_keep(); } else { builder.cmp(); }
+            builder.halt().build().unwrap().start().unwrap();
+            let vm = builder.vm();
+            ((vm.flags().less_than, vm.flags().equal, vm.flags().great_than), vm.stack().to_vec())
+        };
+        for keep in [false, true] {
+            for (l, r, flags) in [(1, 2, (true, false, false)), (2, 2, (false, true, false)), (3, 2, (false, false, true))] {
+                let (actual, stack) = run(l, r, keep);
+                assert_eq!(flags, actual, "{} and {}", l, r);
+                if keep {
+                    assert_eq!(vec![MemoryCell::Value(Value::I32(l)), MemoryCell::Value(Value::I32(r))], stack);
+                } else {
+                    assert!(stack.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn registers_round_trip_through_stack() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I64(40))
+            .st(0)
+            .ld_imm(1, Value::I64(2))
+            .add_r(2, 0, 1)
+            .mov(3, 2)
+            .mul_r(3, 3, 1)
+            .ld(2)
+            .ld(3)
+            .halt()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        let stack: Vec<i64> = builder.results().into_iter().map(|mem| match mem {
+            MemoryCell::Value(Value::I64(n)) => n,
+            _ => panic!("Unexpected stack contents")
+        }).collect();
+        assert_eq!(vec![42, 84], stack);
+    }
+
+    #[test]
+    fn call_and_ret_use_frame_locals() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(7))
+            .push(Value::I32(10))
+            .push(Value::I32(4))
+            .call("Diff", 2)
+            .halt()
+            .label("Diff")
+            .ld_local(0)
+            .ld_local(1)
+            .sub()
+            .st_local(0)
+            .ld_local(0)
+            .ret()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        let stack: Vec<i32> = builder.results().into_iter().map(|mem| match mem {
+            MemoryCell::Value(Value::I32(n)) => n,
+            _ => panic!("Unexpected stack contents")
+        }).collect();
+        assert_eq!(vec![7, 6], stack);
+    }
+
+    #[test]
+    fn call_without_result_leaves_nothing() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(7))
+            .push(Value::I32(10))
+            .push(Value::I32(4))
+            .call("Store", 2)
+            .halt()
+            .label("Store")
+            .ld_local(1)
+            .st(0)
+            .ret()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        assert_eq!(vec![MemoryCell::Value(Value::I32(7))], builder.results());
+        assert_eq!(MemoryCell::Value(Value::I32(4)), builder.vm().registers()[0]);
+    }
+
+    #[test]
+    fn locals_outside_the_frame_are_rejected() {
+        let run = |n: usize| {
+            let mut builder = builder::VMBuilder::new();
+            builder.push(Value::I32(1)).call("Sub", 1).halt().label("Sub").ld_local(n).ret();
+            builder.build().unwrap().start()
+        };
+        assert_eq!(Ok(HaltReason::Halted), run(0));
+        assert!(matches!(run(1), Err(VmError::InvalidLocal(_, 1))));
+        assert!(matches!(run(usize::MAX), Err(VmError::InvalidLocal(_, usize::MAX))));
+    }
+
+    #[test]
+    fn errors_report_pc_and_instruction() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(1))
+            .push(Value::I32(0))
+            .div()
+            .halt()
+            .build()
+            .unwrap();
+
+        match builder.start() {
+            Err(VmError::DivideByZero(fault)) => {
+                assert_eq!(2, fault.pc);
+                assert_eq!(Some(Instruction::Div), fault.instruction);
+            },
+            other => panic!("Expected divide by zero, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn integer_overflow_follows_arithmetic_mode() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(i32::MAX))
+            .push(Value::I32(1))
+            .add()
+            .build()
+            .unwrap();
+        assert!(matches!(builder.start(), Err(VmError::Overflow(_))));
+
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .arithmetic_mode(ArithmeticMode::Wrapping)
+            .push(Value::I32(i32::MIN))
+            .push(Value::I32(-1))
+            .div()
+            .push(Value::I64(i64::MAX))
+            .push(Value::I64(2))
+            .mul_sat()
+            .halt()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        assert_eq!(vec![MemoryCell::Value(Value::I32(i32::MIN)), MemoryCell::Value(Value::I64(i64::MAX))],
+                   builder.results());
+    }
+
+    #[test]
+    fn call_depth_is_limited() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .max_call_depth(8)
+            .label("Forever")
+            .call("Forever", 0)
+            .build()
+            .unwrap();
+
+        assert!(matches!(builder.start(), Err(VmError::CallDepthExceeded(_, 8))));
+    }
+
+    #[test]
+    fn build_rejects_undefined_labels() {
+        let mut builder = builder::VMBuilder::new();
+        builder.jump("Nowhere");
+
+        assert_eq!(Some(BuildError::UnresolvedLabel(String::from("Nowhere"), 0)), builder.build().err());
+    }
+
+    #[test]
+    fn forward_label_resolves_every_reference() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(0))
+            .push(Value::I32(1))
+            .add()
+            .jpos("End")
+            .push(Value::I32(99))
+            .jump("End")
+            .push(Value::I32(98))
+            .label("End")
+            .halt()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        assert_eq!(1, builder.results().len());
+    }
+
+
+    /// Collects everything sent to it
+    struct Recorder(Rc<RefCell<Vec<Value>>>);
+
+    impl Port for Recorder {
+        fn send(&mut self, message: Message) -> Result<(), String> {
+            self.0.borrow_mut().push(message.value);
+            Ok(())
+        }
+    }
+
+    /// Collects whole messages
+    struct Mailbox(Rc<RefCell<Vec<Message>>>);
+
+    impl Port for Mailbox {
+        fn send(&mut self, message: Message) -> Result<(), String> {
+            self.0.borrow_mut().push(message);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn each_vm_owns_its_ports() {
+        let message = |n| Message { from: 0, to: 0, value: Value::I32(n) };
+        let first = Rc::new(RefCell::new(vec![]));
+        let second = Rc::new(RefCell::new(vec![]));
+
+        let mut a = builder::VMBuilder::new();
+        a.port(1, Recorder(first.clone())).out(1, message(1)).halt();
+        let mut b = builder::VMBuilder::new();
+        b.port(1, Recorder(second.clone())).out(1, message(2)).out(1, message(3)).halt();
+
+        a.build().unwrap().start().unwrap();
+        b.build().unwrap().start().unwrap();
+        assert_eq!(vec![Value::I32(1)], *first.borrow());
+        assert_eq!(vec![Value::I32(2), Value::I32(3)], *second.borrow());
+
+        let mut c = builder::VMBuilder::new();
+        let err = c.out(1, message(4)).halt().build().unwrap().start().unwrap_err();
+        assert_eq!(VmError::NoDevice(Fault { pc: 0, instruction: Some(Instruction::Out(1, message(4))) }, 1), err);
+    }
+
+    #[test]
+    fn input_reads_inbox_then_device() {
+        let message = |n| Message { from: 7, to: 0, value: Value::I32(n) };
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .port(2, MessageHandler { sender: Box::new(|_| ()), receiver: Box::new(move || Some(message(5))) })
+            .input(2)
+            .input(2)
+            .try_input(3)
+            .halt();
+        builder.vm_mut().feed(2, message(4));
+        builder.build().unwrap().start().unwrap();
+
+        assert_eq!(vec![MemoryCell::Value(Value::I32(4)), MemoryCell::Value(Value::I32(5))], builder.results());
+        assert!(builder.vm().flags().zero);
+    }
+
+    #[test]
+    fn blocking_input_waits_for_the_host() {
+        let mut builder = builder::VMBuilder::new();
+        builder.input(1).halt();
+        let err = builder.build().unwrap().start().unwrap_err();
+        assert_eq!(VmError::NoInput(Fault { pc: 0, instruction: Some(Instruction::In(1)) }, 1), err);
+
+        builder.blocking_input(true);
+        assert_eq!(Ok(HaltReason::WaitingForInput(1)), builder.start());
+        assert_eq!(Ok(HaltReason::WaitingForInput(1)), builder.vm_mut().run());
+
+        builder.vm_mut().feed(1, Message { from: 0, to: 0, value: Value::Char('x') });
+        assert_eq!(Ok(HaltReason::Halted), builder.vm_mut().run());
+        assert_eq!(vec![MemoryCell::Value(Value::Char('x'))], builder.results());
+    }
+
+    #[test]
+    fn computed_values_are_sent() {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .id(3)
+            .port(0, Mailbox(sent.clone()))
+            .port(5, Mailbox(sent.clone()))
+            .push(Value::I32(20))
+            .push(Value::I32(22))
+            .add()
+            .out_top(5)
+            .push(Value::Symbol(Rc::new(Value::String(String::from("ok")))))
+            .print()
+            .push(Value::Address(None))
+            .print()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let text = |s: &str| Message { from: 3, to: 0, value: Value::String(String::from(s)) };
+        assert_eq!(vec![
+            Message { from: 3, to: 0, value: Value::I32(42) },
+            text("#ok"),
+            text("null"),
+        ], *sent.borrow());
+        assert_eq!("null", Message { from: 0, to: 0, value: Value::Address(None) }.get_message());
+    }
+
+    #[test]
+    fn heap_cells_are_loaded_and_stored() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .alloc(2)
+            .st(0)
+            .ld(0)
+            .push(Value::I32(7))
+            .store()
+            .ld(0)
+            .load()
+            .ld(0)
+            .free()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        assert_eq!(vec![MemoryCell::Value(Value::I32(7))], builder.results());
+        let stats = builder.vm().heap().stats();
+        assert_eq!((1, 1, 0), (stats.allocations, stats.frees, stats.live_blocks));
+    }
+
+    #[test]
+    fn bad_heap_accesses_raise_exceptions() {
+        let err = run_err(|b| { b.push(Value::Address(None)).load(); });
+        assert!(matches!(err, VmError::NullPointer(_)), "{:?}", err);
+        let err = run_err(|b| { b.alloc(2).push(Value::Address(Some(2))).load(); });
+        assert!(matches!(err, VmError::HeapOutOfBounds(_, 2)), "{:?}", err);
+        let err = run_err(|b| { b.alloc(1).st(0).ld(0).free().ld(0).load(); });
+        assert!(matches!(err, VmError::UseAfterFree(_, 0)), "{:?}", err);
+        let err = run_err(|b| { b.alloc(2).push(Value::Address(Some(1))).free(); });
+        assert!(matches!(err, VmError::InvalidFree(_, 1)), "{:?}", err);
+        let err = run_err(|b| { b.alloc(2_000_000_000); });
+        assert!(matches!(err, VmError::OutOfMemory(_)), "{:?}", err);
+        let err = run_err(|b| { b.max_heap_cells(4).alloc(3).alloc(2); });
+        assert!(matches!(err, VmError::OutOfMemory(_)), "{:?}", err);
+        let err = run_err(|b| { b.gc_threshold(8).alloc(usize::MAX); });
+        assert!(matches!(err, VmError::OutOfMemory(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn unread_input_keeps_blocks_alive() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .alloc(1)
+            .push(Value::I32(7))
+            .store()
+            .gc()
+            .input(1)
+            .load()
+            .halt();
+        builder.vm_mut().feed(1, Message { from: 0, to: 0, value: Value::Address(Some(0)) });
+        builder.build().unwrap().start().unwrap();
+
+        assert_eq!(vec![MemoryCell::Value(Value::I32(7))], builder.results());
+        assert_eq!(0, builder.vm().heap().gc_stats().blocks_freed);
+    }
+
+    #[test]
+    fn automatic_gc_reclaims_unreachable_blocks() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .gc_threshold(8)
+            .alloc(1)
+            .st(0)
+            .push(Value::I32(100))
+            .st(1)
+            .push(Value::I32(1))
+            .st(2)
+            .label("Loop")
+            .alloc(4)
+            .st(3)
+            .sub_r(1, 1, 2)
+            .jnz("Loop")
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let heap = builder.vm().heap();
+        assert!(heap.gc_stats().collections >= 40);
+        assert!(heap.stats().capacity <= 13);
+        assert_eq!(Some(0), heap.blocks().next().map(|(base, _)| base));
+    }
+
+    #[test]
+    fn collections_are_built_and_updated() {
+        let s = |text: &str| Value::String(String::from(text));
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(1))
+            .push(Value::I32(2))
+            .new_array(2)
+            .push(Value::I32(3))
+            .append()
+            .push(Value::I64(0))
+            .push(s("first"))
+            .set_index()
+            .st(0)
+            .ld(0)
+            .push(Value::I32(2))
+            .index()
+            .ld(0)
+            .len()
+            .push(s("k"))
+            .push(Value::Bool(true))
+            .new_map(1)
+            .push(s("k"))
+            .push(Value::Bool(false))
+            .map_set()
+            .push(s("k"))
+            .map_get()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let results: Vec<MemoryCell> = [Value::I32(3), Value::I32(3), Value::Bool(false)]
+            .into_iter().map(MemoryCell::Value).collect();
+        assert_eq!(results, builder.results());
+        let array = builder.vm().registers()[0].clone();
+        assert_eq!(MemoryCell::Value(Value::Array(vec![s("first"), Value::I32(2), Value::I32(3)])), array);
+        let message = Message { from: 0, to: 0, value: Value::Tuple(vec![s("a"), Value::Char('b'), Value::Array(vec![])]) };
+        assert_eq!("(\"a\", 'b', [])", message.get_message());
+    }
+
+    #[test]
+    fn bad_collection_access_raises_exceptions() {
+        let err = run_err(|b| { b.new_array(0).push(Value::I32(-1)).index(); });
+        assert!(matches!(err, VmError::IndexOutOfRange(_, -1, 0)), "{:?}", err);
+        let err = run_err(|b| { b.push(Value::I32(1)).new_tuple(1).push(Value::I32(0)).push(Value::I32(5)).set_index(); });
+        assert!(matches!(err, VmError::TypeMismatch(_, _)), "{:?}", err);
+        let err = run_err(|b| { b.new_map(0).push(Value::I32(1)).map_get(); });
+        assert!(matches!(err, VmError::MissingKey(_, ref key) if key == "1"), "{:?}", err);
+        let err = run_err(|b| { b.push(Value::I32(1)).new_array(2); });
+        assert!(matches!(err, VmError::StackUnderflow(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn strings_index_by_char() {
+        let s = |text: &str| Value::String(String::from(text));
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(s("héllo wörld"))
+            .st(0)
+            .ld(0).push(Value::I32(6)).push(Value::I32(5)).substr()
+            .ld(0).str_len()
+            .ld(0).push(Value::I32(1)).char_at()
+            .ld(0).push(Value::Char('w')).find()
+            .ld(0).push(Value::Char(' ')).split()
+            .push(s("Ab")).push(Value::Char('ß')).concat().to_upper()
+            .push(s("{} + {} = {{{}}}")).push(Value::I32(1)).push(Value::F64(1.5)).push(s("x")).format(3)
+            .push(s("apple")).push(s("banana")).str_cmp()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let expected: Vec<MemoryCell> = [
+            s("wörld"),
+            Value::I32(11),
+            Value::Char('é'),
+            Value::I32(6),
+            Value::Array(vec![s("héllo"), s("wörld")]),
+            s("ABSS"),
+            s("1 + 1.5 = {x}"),
+            Value::I32(-1),
+        ].into_iter().map(MemoryCell::Value).collect();
+        assert_eq!(expected, builder.results());
+        assert!(builder.vm().flags().less_than);
+    }
+
+    #[test]
+    fn string_indexes_are_checked() {
+        let s = |text: &str| Value::String(String::from(text));
+
+        let err = run_err(|b| { b.push(s("né")).push(Value::I32(2)).char_at(); });
+        assert!(matches!(err, VmError::IndexOutOfRange(_, 2, 2)), "{:?}", err);
+        let err = run_err(|b| { b.push(s("né")).push(Value::I32(1)).push(Value::I32(2)).substr(); });
+        assert!(matches!(err, VmError::IndexOutOfRange(_, 3, 3)), "{:?}", err);
+        let err = run_err(|b| { b.push(s("abc")).push(Value::I32(1)).push(Value::I64(i64::MAX)).substr(); });
+        assert!(matches!(err, VmError::IndexOutOfRange(_, i64::MAX, 3)), "{:?}", err);
+        let err = run_err(|b| { b.push(s("{} {}")).push(Value::I32(1)).format(1); });
+        assert!(matches!(err, VmError::TypeMismatch(_, _)), "{:?}", err);
+        let err = run_err(|b| { b.push(Value::I32(1)).str_len(); });
+        assert!(matches!(err, VmError::TypeMismatch(_, _)), "{:?}", err);
+    }
+
+    #[test]
+    fn conv_and_type_of_handle_mixed_types() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(2))
+            .push(Value::String(String::from("40")))
+            .conv(ValueType::I32)
+            .add()
+            .conv(ValueType::F64)
+            .type_of()
+            .push(Value::Symbol(Rc::new(Value::String(String::from("f64")))))
+            .cmp()
+            .push(Value::I64(1 << 33))
+            .conv(ValueType::I32)
+            .halt();
+        let err = builder.build().unwrap().start().unwrap_err();
+
+        assert!(builder.vm().flags().equal);
+        assert_eq!(VmError::Overflow(Fault { pc: 9, instruction: Some(Instruction::Conv(ValueType::I32)) }), err);
+    }
+
+    #[test]
+    fn bitwise_ops_set_result_flags() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(0b1100))
+            .push(Value::I32(0b1010))
+            .xor()
+            .push(Value::I32(4))
+            .shl()
+            .st(0)
+            .push(Value::I32(-16))
+            .push(Value::I32(2))
+            .shr()
+            .st(1)
+            .push(Value::I64(-16))
+            .push(Value::I32(2))
+            .sar()
+            .st(2)
+            .push(Value::I32(1))
+            .push(Value::I32(-1))
+            .rotr()
+            .not()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let vm = builder.vm();
+        assert_eq!(MemoryCell::Value(Value::I32(0b0110_0000)), vm.registers()[0]);
+        assert_eq!(MemoryCell::Value(Value::I32(0x3FFF_FFFC)), vm.registers()[1]);
+        assert_eq!(MemoryCell::Value(Value::I64(-4)), vm.registers()[2]);
+        assert_eq!(&[MemoryCell::Value(Value::I32(-3))], vm.stack());
+        assert!(vm.flags().neg && !vm.flags().zero);
+    }
+
+    #[test]
+    fn logical_and_shift_errors() {
+        let err = run_err(|b| { b.push(Value::I32(1)).push(Value::I32(32)).shl(); });
+        assert!(matches!(err, VmError::Overflow(_)));
+        let err = run_err(|b| { b.push(Value::I32(1)).push(Value::U32(1)).and(); });
+        assert!(matches!(err, VmError::TypeMismatch(..)));
+
+        let mut builder = builder::VMBuilder::new();
+        builder.push(Value::Bool(true)).push(Value::Bool(false)).land().lnot().push(Value::I32(1)).lor().halt();
+        let err = builder.build().unwrap().start().unwrap_err();
+        assert!(matches!(err, VmError::TypeMismatch(..)));
+        let flags = builder.vm().flags();
+        assert!(flags.pos && !flags.zero);
+    }
+
+    #[test]
+    fn stack_ops_rearrange_values() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(1))
+            .push(Value::I32(2))
+            .push(Value::I32(3))
+            .rot()
+            .over()
+            .swap()
+            .dup2()
+            .pick(5)
+            .roll(6)
+            .depth()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let values: Vec<MemoryCell> = [3, 3, 1, 3, 1, 2, 2, 7]
+            .into_iter()
+            .map(|n| MemoryCell::Value(Value::I32(n)))
+            .collect();
+        assert_eq!(values, builder.vm().stack());
+    }
+
+    #[test]
+    fn stack_ops_raise_underflow() {
+        assert!(matches!(run_err(|b| { b.pop(); }), VmError::StackUnderflow(_)));
+        assert!(matches!(run_err(|b| { b.dup(); }), VmError::StackUnderflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(1)).dup2(); }), VmError::StackUnderflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(1)).swap(); }), VmError::StackUnderflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(1)).over(); }), VmError::StackUnderflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(1)).push(Value::I32(2)).rot(); }), VmError::StackUnderflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(1)).pick(1); }), VmError::StackUnderflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(1)).clear().roll(0); }), VmError::StackUnderflow(_)));
+    }
+
+    #[test]
+    fn math_ops_cover_integers_and_floats() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::I32(-7))
+            .push(Value::I32(3))
+            .modulo()
+            .push(Value::I32(-7))
+            .push(Value::I32(3))
+            .rem()
+            .push(Value::I64(-3))
+            .push(Value::I64(5))
+            .pow()
+            .abs()
+            .push(Value::F64(2.0))
+            .sqrt()
+            .push(Value::F64(1.5))
+            .max()
+            .round()
+            .push(Value::F32(-2.5))
+            .floor()
+            .neg()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let values = [Value::I32(2), Value::I32(-1), Value::I64(243), Value::F64(2.0), Value::F32(3.0)];
+        let values: Vec<MemoryCell> = values.into_iter().map(MemoryCell::Value).collect();
+        assert_eq!(values, builder.vm().stack());
+        assert!(builder.vm().flags().pos && !builder.vm().flags().non_finite);
+    }
+
+    #[test]
+    fn float_results_report_nan_and_infinity() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::F64(-1.0))
+            .sqrt()
+            .is_nan()
+            .st(0)
+            .push(Value::F32(0.0))
+            .ln()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let vm = builder.vm();
+        assert_eq!(MemoryCell::Value(Value::Bool(true)), vm.registers()[0]);
+        assert_eq!(&[MemoryCell::Value(Value::F32(f32::NEG_INFINITY))], vm.stack());
+        assert!(vm.flags().non_finite && vm.flags().neg);
+
+        assert!(matches!(run_err(|b| { b.push(Value::I32(i32::MIN)).neg(); }), VmError::Overflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(2)).push(Value::I32(-1)).pow(); }), VmError::TypeMismatch(..)));
+        assert!(matches!(run_err(|b| { b.push(Value::I64(1)).push(Value::I64(0)).modulo(); }), VmError::DivideByZero(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::I32(4)).sqrt(); }), VmError::TypeMismatch(..)));
+    }
+
+    #[test]
+    fn arithmetic_errors_name_their_operator() {
+        let err = run_err(|b| { b.push(Value::I32(1)).push(Value::F32(1.0)).sub(); });
+        assert!(matches!(&err, VmError::TypeMismatch(_, msg) if msg.starts_with("Sub: operands must be numbers")), "{:?}", err);
+        let err = run_err(|b| { b.push(Value::F64(1.0)).push(Value::F64(1.0)).xor(); });
+        assert!(matches!(&err, VmError::TypeMismatch(_, msg) if msg.starts_with("Xor: operands must be integers")), "{:?}", err);
+        let err = run_err(|b| { b.push(Value::Bool(true)).abs(); });
+        assert!(matches!(&err, VmError::TypeMismatch(_, msg) if msg.starts_with("Abs: expected a number")), "{:?}", err);
+    }
+
+    #[test]
+    fn sized_integers_and_bytes_run() {
+        let mut builder = builder::VMBuilder::new();
+        builder
+            .push(Value::U8(250))
+            .push(Value::U8(10))
+            .add_wrap()
+            .st(0)
+            .push(Value::I16(-1))
+            .push(Value::I64(-1))
+            .cmp()
+            .push(Value::U32(0x8000_0000))
+            .push(Value::I32(31))
+            .shr()
+            .st(1)
+            .push(Value::Bytes(vec![1, 2]))
+            .push(Value::I32(255))
+            .append()
+            .push(Value::Bytes(vec![9]))
+            .concat()
+            .push(Value::I32(3))
+            .index()
+            .halt();
+        builder.build().unwrap().start().unwrap();
+
+        let vm = builder.vm();
+        assert_eq!(MemoryCell::Value(Value::U8(4)), vm.registers()[0]);
+        assert_eq!(MemoryCell::Value(Value::U32(1)), vm.registers()[1]);
+        assert_eq!(&[MemoryCell::Value(Value::U8(9))], vm.stack());
+        assert!(vm.flags().equal);
+
+        assert!(matches!(run_err(|b| { b.push(Value::U16(1)).push(Value::U16(2)).sub(); }), VmError::Overflow(_)));
+        assert!(matches!(run_err(|b| { b.push(Value::U8(1)).push(Value::I8(1)).add(); }), VmError::TypeMismatch(..)));
+        assert!(matches!(run_err(|b| { b.push(Value::Bytes(vec![])).push(Value::I32(256)).append(); }), VmError::Overflow(_)));
+    }
+
+    /// Runs a program, halted at its end, with both `run` and `run_lowered`
+    /// and checks that they leave the VM in the same state
+    fn assert_runs_alike(build: impl Fn(&mut VMBuilder)) {
+        let sent = [Rc::new(RefCell::new(vec![])), Rc::new(RefCell::new(vec![]))];
+        let mut interpreted = VMBuilder::new();
+        let mut lowered = VMBuilder::new();
+        for (b, sent) in [&mut interpreted, &mut lowered].into_iter().zip(&sent) {
+            b.port(0, Recorder(sent.clone())).port(1, Recorder(sent.clone()));
+            build(b);
+            b.halt().build().unwrap();
+        }
+        let program = interpreted.vm().memory().to_vec();
+        assert_eq!(interpreted.start(), lowered.start_lowered(), "{:?}", program);
+        assert_eq!(sent[0], sent[1], "{:?}", program);
+        let (expected, actual) = (interpreted.vm(), lowered.vm());
+        assert_eq!(expected.stack(), actual.stack(), "{:?}", program);
+        assert_eq!(expected.registers(), actual.registers(), "{:?}", program);
+        assert_eq!(expected.pc(), actual.pc(), "{:?}", program);
+        assert_eq!(format!("{:?}", expected.flags()), format!("{:?}", actual.flags()), "{:?}", program);
+        let (expected, actual) = (expected.heap(), actual.heap());
+        assert_eq!(expected.stats(), actual.stats(), "{:?}", program);
+        assert_eq!(expected.blocks().collect::<Vec<_>>(), actual.blocks().collect::<Vec<_>>(), "{:?}", program);
+        assert_eq!(expected.gc_stats().blocks_freed, actual.gc_stats().blocks_freed, "{:?}", program);
+    }
+
+    #[test]
+    fn lowered_programs_run_like_the_interpreter() {
+        let programs: Vec<fn(&mut builder::VMBuilder)> = vec![
+            |b| {
+                b.push(Value::I32(100))
+                    .st(1)
+                    .ld_imm(0, Value::I32(0))
+                    .ld_imm(2, Value::I32(-1))
+                    .label("Loop")
+                    .add_r(0, 0, 1)
+                    .add_r(1, 1, 2)
+                    .jnz("Loop")
+                    .push(Value::String("sum ".to_string()))
+                    .ld(0)
+                    .conv(ValueType::String)
+                    .concat()
+                    .out(1, Message { from: 0, to: 1, value: Value::Char('!') });
+            },
+            |b| {
+                b.push(Value::I64(21))
+                    .call("Twice", 1)
+                    .halt()
+                    .label("Twice")
+                    .ld_local(0)
+                    .dup()
+                    .add()
+                    .ret();
+            },
+            |b| { b.push(Value::I32(i32::MAX)).push(Value::I32(1)).add(); },
+            |b| { b.push(Value::I32(1)).cell(MemoryCell::Value(Value::I32(2))); },
+            |b| { b.instruction(Instruction::Pick(usize::MAX)); },
+            |b| { b.blocking_input(true).input(1); },
+        ];
+        for program in programs {
+            assert_runs_alike(program);
+        }
+    }
+
+    #[test]
+    fn every_instruction_runs_alike_lowered() {
+        use crate::rvm::lowered::{Opcode, Program};
+        use Instruction::*;
+
+        let int = |n: i32| Push(Value::I32(n));
+        let text = |s: &str| Push(Value::String(s.to_string()));
+        let float = |x: f64| Push(Value::F64(x));
+        let less = vec![int(1), int(2), Cmp];
+        let to = Value::Address(None);
+        let message = Message { from: 0, to: 1, value: Value::Char('x') };
+        let map = vec![text("k"), int(1), NewMap(1)];
+        let cases: Vec<(Vec<Instruction>, Instruction)> = vec![
+            (vec![], Nop),
+            (vec![], Push(Value::Array(vec![Value::I32(1)]))),
+            (vec![int(1)], Pop),
+            (vec![int(7), int(3)], Add),
+            (vec![int(7), int(3)], Sub),
+            (vec![int(7), int(3)], Mul),
+            (vec![int(7), int(3)], Div),
+            (vec![Push(Value::U8(250)), Push(Value::U8(10))], AddWrap),
+            (vec![Push(Value::U8(5)), Push(Value::U8(10))], SubWrap),
+            (vec![Push(Value::U8(50)), Push(Value::U8(10))], MulWrap),
+            (vec![Push(Value::I8(i8::MIN)), Push(Value::I8(-1))], DivWrap),
+            (vec![Push(Value::U8(250)), Push(Value::U8(10))], AddSat),
+            (vec![Push(Value::U8(5)), Push(Value::U8(10))], SubSat),
+            (vec![Push(Value::U8(50)), Push(Value::U8(10))], MulSat),
+            (vec![Push(Value::I8(i8::MIN)), Push(Value::I8(-1))], DivSat),
+            (vec![LdImm(1, Value::I32(6)), LdImm(2, Value::I32(3))], AddR(0, 1, 2)),
+            (vec![LdImm(1, Value::I32(6)), LdImm(2, Value::I32(3))], SubR(0, 1, 2)),
+            (vec![LdImm(1, Value::I32(6)), LdImm(2, Value::I32(3))], MulR(0, 1, 2)),
+            (vec![LdImm(1, Value::I32(6)), LdImm(2, Value::I32(3))], DivR(0, 1, 2)),
+            (vec![LdImm(1, Value::I32(6))], Ld(1)),
+            (vec![int(5)], St(1)),
+            (vec![LdImm(1, Value::I32(6))], Mov(0, 1)),
+            (vec![], LdImm(2, Value::F64(0.5))),
+            (vec![], Jmp(to.clone())),
+            (less.clone(), Jz(to.clone())),
+            (less.clone(), Jnz(to.clone())),
+            (less.clone(), Jneg(to.clone())),
+            (less.clone(), Jnneg(to.clone())),
+            (less.clone(), Jpos(to.clone())),
+            (less.clone(), Jnpos(to.clone())),
+            (less.clone(), Jeq(to.clone())),
+            (less.clone(), Jne(to.clone())),
+            (less.clone(), Jlt(to.clone())),
+            (less.clone(), Jnlt(to.clone())),
+            (less.clone(), Jgt(to.clone())),
+            (less.clone(), Jngt(to.clone())),
+            (vec![int(4)], Call(to.clone(), 1)),
+            (vec![], Ret),
+            (vec![], LdLocal(0)),
+            (vec![int(1)], StLocal(0)),
+            (vec![], Alloc(2)),
+            (vec![Alloc(1)], Free),
+            (vec![Alloc(1)], Load),
+            (vec![Alloc(1), int(9)], Store),
+            (vec![Alloc(1), Pop], Gc),
+            (vec![int(1), int(2)], NewArray(2)),
+            (vec![int(1), text("a")], NewTuple(2)),
+            (vec![text("k"), int(1)], NewMap(1)),
+            (vec![int(1), int(2), NewArray(2), int(1)], Index),
+            (vec![int(1), int(2), NewArray(2), int(0), int(5)], SetIndex),
+            (vec![int(1), int(2), NewArray(2)], Len),
+            (vec![int(1), NewArray(1), int(3)], Append),
+            ([map.clone(), vec![text("k")]].concat(), MapGet),
+            ([map.clone(), vec![text("j"), int(2)]].concat(), MapSet),
+            (vec![text("a"), text("b")], Concat),
+            (vec![text("hello"), int(1), int(3)], Substr),
+            (vec![text("hello")], StrLen),
+            (vec![text("hello"), int(1)], CharAt),
+            (vec![text("a"), text("b")], StrCmp),
+            (vec![text("Hello")], ToUpper),
+            (vec![text("Hello")], ToLower),
+            (vec![text("a,b"), text(",")], Split),
+            (vec![text("hello"), text("l")], Find),
+            (vec![text("x={}"), int(1)], Format(1)),
+            (vec![int(3)], Conv(ValueType::String)),
+            (vec![float(1.0)], TypeOf),
+            (vec![int(6), int(3)], And),
+            (vec![int(6), int(3)], Or),
+            (vec![int(6), int(3)], Xor),
+            (vec![int(6)], Not),
+            (vec![int(-8), int(1)], Shl),
+            (vec![int(-8), int(1)], Shr),
+            (vec![int(-8), int(1)], Sar),
+            (vec![int(-8), int(1)], Rotl),
+            (vec![int(-8), int(1)], Rotr),
+            (vec![Push(Value::Bool(true)), Push(Value::Bool(false))], LAnd),
+            (vec![Push(Value::Bool(true)), Push(Value::Bool(false))], LOr),
+            (vec![Push(Value::Bool(true))], LNot),
+            (vec![int(-7), int(3)], Mod),
+            (vec![int(-7), int(3)], Rem),
+            (vec![int(-4)], Neg),
+            (vec![int(-4)], Abs),
+            (vec![int(-4), int(2)], Min),
+            (vec![int(-4), int(2)], Max),
+            (vec![Push(Value::I64(3)), Push(Value::I64(4))], Pow),
+            (vec![float(2.5)], Sqrt),
+            (vec![float(2.5)], Sin),
+            (vec![float(2.5)], Cos),
+            (vec![float(2.5)], Tan),
+            (vec![float(2.5)], Exp),
+            (vec![float(2.5)], Ln),
+            (vec![float(2.5)], Floor),
+            (vec![float(2.5)], Ceil),
+            (vec![float(2.5)], Round),
+            (vec![float(f64::NAN)], IsNaN),
+            (vec![int(1)], Dup),
+            (vec![int(1), int(2)], Dup2),
+            (vec![int(1), int(2)], Swap),
+            (vec![int(1), int(2)], Over),
+            (vec![int(1), int(2), int(3)], Rot),
+            (vec![int(1), int(2)], Pick(1)),
+            (vec![int(1), int(2), int(3)], Roll(2)),
+            (vec![int(1)], Depth),
+            (vec![int(1)], Clear),
+            (vec![int(2), int(1)], Cmp),
+            (vec![int(2), int(1)], CmpKeep),
+            (vec![], Out(1, message)),
+            (vec![int(1)], OutTop(1)),
+            (vec![int(1)], Print),
+            (vec![], In(1)),
+            (vec![], TryIn(1)),
+            (vec![], Halt),
+            (vec![int(1)], Dump),
+        ];
+
+        // the cases cover the whole instruction set, which `Opcode` lists before its extra codes
+        let memory: Vec<_> = cases.iter()
+            .map(|(_, inst)| MemoryCell::Instruction(inst.with_target(0).unwrap_or(inst.clone())))
+            .collect();
+        let mut opcodes: Vec<Opcode> = vec![];
+        for op in Program::lower(&memory).code() {
+            if !opcodes.contains(&op.opcode) {
+                opcodes.push(op.opcode);
+            }
+        }
+        assert_eq!(cases.len(), opcodes.len());
+        assert_eq!(Opcode::Unresolved as usize, opcodes.len());
+
+        for (setup, inst) in cases {
+            assert_runs_alike(|b| {
+                for setup in &setup {
+                    b.instruction(setup.clone());
+                }
+                if inst.is_branch() {
+                    b.instruction_to(inst.clone(), "End");
+                } else {
+                    b.instruction(inst.clone());
+                }
+                b.push(Value::I32(-1)).label("End");
+            });
+        }
+    }
+
+    #[test]
+    fn lowered_program_resumes_after_input() {
+        let mut builder = builder::VMBuilder::new();
+        builder.blocking_input(true).input(1).push(Value::I32(1)).add().halt().build().unwrap();
+        assert_eq!(Ok(HaltReason::WaitingForInput(1)), builder.start_lowered());
+
+        let program = builder.lower().unwrap();
+        let vm = builder.vm_mut();
+        vm.feed(1, Message { from: 0, to: 1, value: Value::I32(41) });
+        assert_eq!(Ok(HaltReason::Halted), vm.run_lowered(&program));
+        assert_eq!(&[MemoryCell::Value(Value::I32(42))], vm.stack());
+
+        assert!(matches!(builder::VMBuilder::new().lower(), Err(VmError::NotBuilt(_))));
+    }
 }
@@ -0,0 +1,325 @@
+//! Standard devices that can be attached to VM ports
+//!
+//! `VMBuilder::standard_devices` wires the console, error console, clock and
+//! random number generator to the ports below. The file device needs a
+//! sandbox directory and is attached explicitly.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::port::Port;
+use super::vm::{Message, Value};
+
+
+pub const CONSOLE_PORT: usize = 0;
+pub const ERROR_PORT: usize = 1;
+pub const CLOCK_PORT: usize = 2;
+pub const RANDOM_PORT: usize = 3;
+
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Writes messages as text to stdout or stderr, and bytes unchanged. The
+/// stdout console also reads lines from stdin.
+pub struct Console {
+    stream: Stream,
+}
+
+impl Console {
+    pub fn stdout() -> Console {
+        Console { stream: Stream::Stdout }
+    }
+
+    pub fn stderr() -> Console {
+        Console { stream: Stream::Stderr }
+    }
+}
+
+impl Port for Console {
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        let data = match message.value {
+            Value::Bytes(bytes) => bytes,
+            value => value.to_string().into_bytes(),
+        };
+        let result = match self.stream {
+            Stream::Stdout => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()),
+            Stream::Stderr => io::stderr().write_all(&data),
+        };
+        result.map_err(|err| err.to_string())
+    }
+
+    /// The next line of stdin without its line ending, or nothing at end of input
+    fn receive(&mut self) -> Option<Message> {
+        if let Stream::Stderr = self.stream {
+            return None;
+        }
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                let line = line.trim_end_matches(['\n', '\r']);
+                Some(Message { from: 0, to: 0, value: Value::String(line.to_string()) })
+            }
+        }
+    }
+}
+
+/// Microseconds elapsed since the device was created, as an I64.
+/// Never goes backwards.
+pub struct Clock {
+    start: Instant,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock { start: Instant::now() }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Port for Clock {
+    fn send(&mut self, _message: Message) -> Result<(), String> {
+        Err(String::from("the clock is read only"))
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        let micros = self.start.elapsed().as_micros() as i64;
+        Some(Message { from: 0, to: 0, value: Value::I64(micros) })
+    }
+}
+
+/// Pseudo random numbers from a xorshift64* generator. Reading yields a
+/// non-negative I32; sending any integer reseeds the generator.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /// A generator that always produces the same sequence for `seed`
+    pub fn new(seed: u64) -> Random {
+        // xorshift never leaves the all zero state
+        Random { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+    }
+
+    /// A generator seeded from the system time
+    pub fn from_time() -> Random {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Random::new(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl Port for Random {
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        match message.value.as_integer() {
+            Some(seed) => *self = Random::new(seed as u64),
+            None => return Err(format!("cannot seed the random generator with {:?}", message.value)),
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        let n = (self.next_u64() >> 33) as i32;
+        Some(Message { from: 0, to: 0, value: Value::I32(n) })
+    }
+}
+
+/// How the next path sent to a `FileDevice` is opened
+#[derive(Clone, Copy)]
+enum OpenMode {
+    Read,
+    Create,
+    Append,
+}
+
+/// Reads and writes files below a root directory.
+///
+/// The program sends the symbol `#"open"`, `#"create"` or `#"append"`
+/// followed by a relative path, then sends values to write them as text
+/// (bytes are written unchanged) or receives to read the file line by line.
+/// `#"close"` closes the file.
+/// Paths that are absolute or contain `..` are rejected.
+pub struct FileDevice {
+    root: PathBuf,
+    pending: Option<OpenMode>,
+    reader: Option<BufReader<File>>,
+    writer: Option<File>,
+}
+
+impl FileDevice {
+    pub fn new(root: impl Into<PathBuf>) -> FileDevice {
+        FileDevice {
+            root: root.into(),
+            pending: None,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    /// Resolves `path` inside the root, refusing anything that could escape it
+    fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let relative = Path::new(path);
+        if path.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("path '{}' is outside the file sandbox", path));
+        }
+        let root = self.root.canonicalize().map_err(|err| format!("file sandbox: {}", err))?;
+        let full = root.join(relative);
+        // a symlink inside the root may still point elsewhere
+        let parent = full.parent().unwrap_or(&root).canonicalize().map_err(|err| format!("{}: {}", path, err))?;
+        let escapes = match full.canonicalize() {
+            Ok(target) => !target.starts_with(&root),
+            Err(_) => !parent.starts_with(&root),
+        };
+        if escapes {
+            return Err(format!("path '{}' is outside the file sandbox", path));
+        }
+        Ok(full)
+    }
+
+    fn open(&mut self, mode: OpenMode, path: &str) -> Result<(), String> {
+        let full = self.resolve(path)?;
+        self.close();
+        let mut options = OpenOptions::new();
+        match mode {
+            OpenMode::Read => options.read(true),
+            OpenMode::Create => options.write(true).create(true).truncate(true),
+            OpenMode::Append => options.append(true).create(true),
+        };
+        let file = options.open(&full).map_err(|err| format!("{}: {}", path, err))?;
+        match mode {
+            OpenMode::Read => self.reader = Some(BufReader::new(file)),
+            _ => self.writer = Some(file),
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.reader = None;
+        self.writer = None;
+    }
+}
+
+impl Port for FileDevice {
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        if let Some(mode) = self.pending.take() {
+            return match &message.value {
+                Value::String(path) => self.open(mode, path),
+                value => Err(format!("expected a file name but received {:?}", value)),
+            };
+        }
+        match &message.value {
+            Value::Symbol(command) => match command.as_ref() {
+                Value::String(s) if s == "open" => self.pending = Some(OpenMode::Read),
+                Value::String(s) if s == "create" => self.pending = Some(OpenMode::Create),
+                Value::String(s) if s == "append" => self.pending = Some(OpenMode::Append),
+                Value::String(s) if s == "close" => self.close(),
+                _ => return Err(format!("unknown file command {}", message.value)),
+            },
+            value => match &mut self.writer {
+                Some(file) => match value {
+                    Value::Bytes(bytes) => file.write_all(bytes),
+                    value => write!(file, "{}", value),
+                }.map_err(|err| err.to_string())?,
+                None => return Err(String::from("no file is open for writing")),
+            },
+        }
+        Ok(())
+    }
+
+    /// The next line of the open file, or nothing at the end of the file
+    fn receive(&mut self) -> Option<Message> {
+        let mut line = String::new();
+        match self.reader.as_mut()?.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                let line = line.trim_end_matches(['\n', '\r']);
+                Some(Message { from: 0, to: 0, value: Value::String(line.to_string()) })
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, rc::Rc};
+
+    use super::*;
+
+    fn message(value: Value) -> Message {
+        Message { from: 0, to: 0, value }
+    }
+
+    fn command(name: &str) -> Message {
+        message(Value::Symbol(Rc::new(Value::String(String::from(name)))))
+    }
+
+    #[test]
+    fn files_stay_inside_the_sandbox() {
+        let root = std::env::temp_dir().join(format!("rvm-files-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let mut device = FileDevice::new(&root);
+
+        device.send(command("create")).unwrap();
+        device.send(message(Value::String(String::from("out.txt")))).unwrap();
+        device.send(message(Value::I32(42))).unwrap();
+        device.send(message(Value::String(String::from("\nsecond")))).unwrap();
+        device.send(command("close")).unwrap();
+        assert_eq!("42\nsecond", fs::read_to_string(root.join("out.txt")).unwrap());
+
+        device.send(command("open")).unwrap();
+        device.send(message(Value::String(String::from("out.txt")))).unwrap();
+        assert_eq!(Some(message(Value::String(String::from("42")))), device.receive());
+        assert_eq!(Some(message(Value::String(String::from("second")))), device.receive());
+        assert_eq!(None, device.receive());
+
+        for path in ["../escape.txt", "/etc/passwd", "a/../../b"] {
+            device.send(command("open")).unwrap();
+            assert!(device.send(message(Value::String(String::from(path)))).is_err(), "{}", path);
+        }
+        assert!(device.send(message(Value::I32(1))).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn seeded_random_numbers_repeat() {
+        let mut a = Random::new(7);
+        let mut b = Random::new(1);
+        b.send(message(Value::I64(7))).unwrap();
+        for _ in 0..10 {
+            let n = a.receive();
+            assert_eq!(n, b.receive());
+            assert!(matches!(n, Some(Message { value: Value::I32(n), .. }) if n >= 0));
+        }
+    }
+
+    #[test]
+    fn clock_is_monotonic() {
+        let mut clock = Clock::new();
+        let time = |m: Option<Message>| match m {
+            Some(Message { value: Value::I64(t), .. }) => t,
+            other => panic!("unexpected {:?}", other),
+        };
+        let first = time(clock.receive());
+        assert!(time(clock.receive()) >= first);
+        assert!(clock.send(message(Value::I32(0))).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Errors raised while building and running programs
+
+use std::fmt;
+
+use super::vm::Instruction;
+
+
+/// Where the VM was when an error was raised
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub pc: usize,
+    pub instruction: Option<Instruction>,
+}
+
+/// Errors that stop the virtual machine
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    NotBuilt(Fault),
+    StackUnderflow(Fault),
+    TypeMismatch(Fault, String),
+    DivideByZero(Fault),
+    Overflow(Fault),
+    InvalidInstruction(Fault),
+    PcOutOfBounds(Fault),
+    UnresolvedLabel(Fault),
+    NoDevice(Fault, usize),                         // port
+    Device(Fault, usize, String),                   // port, device error
+    NoInput(Fault, usize),                          // port
+    NullPointer(Fault),
+    HeapOutOfBounds(Fault, usize),                  // address
+    UseAfterFree(Fault, usize),                     // address
+    InvalidFree(Fault, usize),                      // address
+    OutOfMemory(Fault),
+    IndexOutOfRange(Fault, i64, usize),             // index, length
+    MissingKey(Fault, String),                      // key
+    ConversionFailed(Fault, String),
+    InvalidRegister(Fault, usize),                  // register
+    EmptyRegister(Fault, usize),                    // register
+    CallDepthExceeded(Fault, usize),                // maximum depth
+    ArgumentCount(Fault, usize, usize),             // expected, found
+    InvalidLocal(Fault, usize),                     // local index
+    ReturnWithoutCall(Fault),
+}
+
+impl VmError {
+    pub fn fault(&self) -> &Fault {
+        match self {
+            VmError::NotBuilt(f)
+            | VmError::StackUnderflow(f)
+            | VmError::TypeMismatch(f, _)
+            | VmError::DivideByZero(f)
+            | VmError::Overflow(f)
+            | VmError::InvalidInstruction(f)
+            | VmError::PcOutOfBounds(f)
+            | VmError::UnresolvedLabel(f)
+            | VmError::NoDevice(f, _)
+            | VmError::Device(f, _, _)
+            | VmError::NoInput(f, _)
+            | VmError::NullPointer(f)
+            | VmError::HeapOutOfBounds(f, _)
+            | VmError::UseAfterFree(f, _)
+            | VmError::InvalidFree(f, _)
+            | VmError::OutOfMemory(f)
+            | VmError::IndexOutOfRange(f, _, _)
+            | VmError::MissingKey(f, _)
+            | VmError::ConversionFailed(f, _)
+            | VmError::InvalidRegister(f, _)
+            | VmError::EmptyRegister(f, _)
+            | VmError::CallDepthExceeded(f, _)
+            | VmError::ArgumentCount(f, _, _)
+            | VmError::InvalidLocal(f, _)
+            | VmError::ReturnWithoutCall(f) => f
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.fault().pc
+    }
+
+    pub fn instruction(&self) -> Option<&Instruction> {
+        self.fault().instruction.as_ref()
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Exception({}): ", self.pc())?;
+        match self {
+            VmError::NotBuilt(_) => write!(f, "program must be built before it is started"),
+            VmError::StackUnderflow(_) => write!(f, "stack underflow"),
+            VmError::TypeMismatch(_, msg) => write!(f, "{}", msg),
+            VmError::DivideByZero(_) => write!(f, "divide by zero"),
+            VmError::Overflow(_) => write!(f, "integer overflow"),
+            VmError::InvalidInstruction(_) => write!(f, "invalid instruction in memory cell"),
+            VmError::PcOutOfBounds(_) => write!(f, "program counter is outside of memory"),
+            VmError::UnresolvedLabel(_) => write!(f, "jump target was never resolved"),
+            VmError::NoDevice(_, port) => write!(f, "no device attached to port {}", port),
+            VmError::Device(_, port, msg) => write!(f, "device on port {}: {}", port, msg),
+            VmError::NoInput(_, port) => write!(f, "no input available on port {}", port),
+            VmError::NullPointer(_) => write!(f, "null address dereferenced"),
+            VmError::HeapOutOfBounds(_, address) => write!(f, "address {} is not allocated", address),
+            VmError::UseAfterFree(_, address) => write!(f, "address {} was used after it was freed", address),
+            VmError::InvalidFree(_, address) => write!(f, "address {} is not the start of a heap block", address),
+            VmError::OutOfMemory(_) => write!(f, "heap memory exhausted"),
+            VmError::IndexOutOfRange(_, index, len) => write!(f, "index {} is out of range for length {}", index, len),
+            VmError::MissingKey(_, key) => write!(f, "key {} is not in the map", key),
+            VmError::ConversionFailed(_, msg) => write!(f, "conversion failed: {}", msg),
+            VmError::InvalidRegister(_, reg) => write!(f, "register r{} does not exist", reg),
+            VmError::EmptyRegister(_, reg) => write!(f, "register r{} is empty", reg),
+            VmError::CallDepthExceeded(_, depth) => write!(f, "maximum call depth of {} exceeded", depth),
+            VmError::ArgumentCount(_, expected, found) =>
+                write!(f, "call expected {} arguments but the stack holds {}", expected, found),
+            VmError::InvalidLocal(_, n) => write!(f, "local {} is outside the current frame", n),
+            VmError::ReturnWithoutCall(_) => write!(f, "return with an empty call stack"),
+        }?;
+        if let Some(inst) = self.instruction() {
+            write!(f, " at {:?}", inst)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Why `run` or `step` handed control back to the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum HaltReason {
+    Halted,                 // a Halt instruction was executed
+    Stepped,                // step executed one instruction and the VM can continue
+    WaitingForInput(usize), // a blocking In found no input on the port; feed it and resume
+}
+
+/// Errors raised while building a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    UnresolvedLabel(String, usize),                 // label, referencing address
+    InvalidInstruction(usize),                      // address of a cell that takes no label
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::UnresolvedLabel(label, address) =>
+                write!(f, "label '{}' referenced at {} is never defined", label, address),
+            BuildError::InvalidInstruction(address) =>
+                write!(f, "invalid instruction at {}", address),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Reasons a program image is rejected by `RustyVM::load_image`.
+/// Offsets are byte positions in the image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated(usize),                               // offset where more bytes were needed
+    InvalidValueTag(usize, u8),                     // offset, tag
+    InvalidCellKind(usize, u8),                     // offset, kind
+    InvalidOpcode(usize, u16),                      // offset, opcode
+    InvalidType(usize, usize),                      // offset, value type index
+    InvalidUtf8(usize),                             // offset of the string
+    InvalidChar(usize, u32),                        // offset, code point
+    ConstantOutOfRange(usize, u32),                 // offset, constant index
+    MetadataOutOfRange(usize, u32),                 // offset, metadata index
+    SymbolOutOfRange(String, u64),                  // symbol, address
+    TrailingBytes(usize),                           // offset of the first unread byte
+    TooDeep(usize),                                 // offset of the value nested too deeply
+    Overflow(usize, u64),                           // offset, value that does not fit a usize
+    TooLarge(usize),                                // length or count that does not fit a u32
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::BadMagic => write!(f, "not a Rusty VM image"),
+            ImageError::UnsupportedVersion(version) => write!(f, "unsupported image version {}", version),
+            ImageError::Truncated(offset) => write!(f, "image truncated at byte {}", offset),
+            ImageError::InvalidValueTag(offset, tag) => write!(f, "invalid value tag {} at byte {}", tag, offset),
+            ImageError::InvalidCellKind(offset, kind) => write!(f, "invalid memory cell kind {} at byte {}", kind, offset),
+            ImageError::InvalidOpcode(offset, opcode) => write!(f, "invalid opcode {} at byte {}", opcode, offset),
+            ImageError::InvalidType(offset, index) => write!(f, "invalid value type {} at byte {}", index, offset),
+            ImageError::InvalidUtf8(offset) => write!(f, "string at byte {} is not valid UTF-8", offset),
+            ImageError::InvalidChar(offset, c) => write!(f, "invalid character {:#x} at byte {}", c, offset),
+            ImageError::ConstantOutOfRange(offset, index) =>
+                write!(f, "constant {} referenced at byte {} is not in the constant pool", index, offset),
+            ImageError::MetadataOutOfRange(offset, index) =>
+                write!(f, "metadata {} referenced at byte {} does not exist", index, offset),
+            ImageError::SymbolOutOfRange(name, address) =>
+                write!(f, "symbol '{}' points outside the program at {}", name, address),
+            ImageError::TrailingBytes(offset) => write!(f, "unexpected data after the image at byte {}", offset),
+            ImageError::TooDeep(offset) => write!(f, "value at byte {} is nested too deeply", offset),
+            ImageError::Overflow(offset, n) => write!(f, "value {} at byte {} is too large for this platform", n, offset),
+            ImageError::TooLarge(len) => write!(f, "length {} is too large for an image", len),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
@@ -0,0 +1,337 @@
+//! Heap memory for the Alloc, Free, Load and Store instructions
+//!
+//! The heap is a vector of value cells handed out in blocks. Addresses are
+//! cell indexes, so any cell inside a live block can be loaded or stored.
+//! Freed blocks are reused first fit and merged with free neighbours. The
+//! heap only grows up to a cell limit, so a guest cannot exhaust the host.
+//!
+//! Blocks can also be reclaimed by a mark and sweep collector. It marks
+//! every block reachable through `Value::Address` from the roots the VM
+//! passes in, then frees the rest. Code addresses look like heap addresses,
+//! so a block may occasionally be kept alive longer than needed.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    time::{Duration, Instant},
+};
+
+use super::vm::Value;
+
+
+/// Why a heap access was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapError {
+    Null,
+    OutOfBounds(usize),
+    UseAfterFree(usize),
+    InvalidFree(usize),         // address is not the start of a live block
+    OutOfMemory(usize),         // cells requested
+}
+
+/// Default limit on the cells the heap may claim from the host
+pub const DEFAULT_MAX_HEAP_CELLS: usize = 1 << 22;
+
+/// Running totals shown by `Dump`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeapStats {
+    pub allocations: usize,
+    pub frees: usize,
+    pub live_blocks: usize,
+    pub live_cells: usize,
+    pub capacity: usize,        // cells ever claimed from the host, live or free
+}
+
+/// Garbage collector counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub blocks_freed: usize,
+    pub bytes_freed: usize,
+    pub total_pause: Duration,
+    pub last_pause: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Heap {
+    cells: Vec<Value>,
+    live: BTreeMap<usize, usize>,   // base -> length of allocated blocks
+    free: BTreeMap<usize, usize>,   // base -> length of blocks available for reuse
+    allocations: usize,
+    frees: usize,
+    gc_threshold: Option<usize>,    // cells allocated between automatic collections
+    allocated_since_gc: usize,
+    max_cells: Option<usize>,       // None for DEFAULT_MAX_HEAP_CELLS
+    gc: GcStats,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    /// Enables automatic collection once `cells` have been allocated since
+    /// the last one, or disables it with `None`
+    pub fn set_gc_threshold(&mut self, cells: Option<usize>) {
+        self.gc_threshold = cells;
+    }
+
+    /// Limits the cells the heap may claim from the host, so that a guest
+    /// program cannot exhaust the host's memory
+    pub fn set_max_cells(&mut self, cells: usize) {
+        self.max_cells = Some(cells);
+    }
+
+    pub fn max_cells(&self) -> usize {
+        self.max_cells.unwrap_or(DEFAULT_MAX_HEAP_CELLS)
+    }
+
+    /// True if allocating `len` more cells should trigger a collection first
+    pub fn wants_collection(&self, len: usize) -> bool {
+        self.gc_threshold.is_some_and(|threshold| self.allocated_since_gc.saturating_add(len) > threshold)
+    }
+
+    /// Allocates `len` cells initialised to null and returns the base address.
+    /// Fails if the heap would grow past its limit or the host refuses the memory.
+    pub fn alloc(&mut self, len: usize) -> Result<usize, HeapError> {
+        let reuse = self.free.iter().find(|(_, free)| **free >= len).map(|(base, free)| (*base, *free));
+        let base = match reuse {
+            Some((base, free)) => {
+                self.free.remove(&base);
+                if free > len {
+                    self.free.insert(base + len, free - len);
+                }
+                base
+            },
+            None => {
+                let base = self.cells.len();
+                match base.checked_add(len) {
+                    Some(end) if end <= self.max_cells() && self.cells.try_reserve(len).is_ok() =>
+                        self.cells.resize(end, Value::Address(None)),
+                    _ => return Err(HeapError::OutOfMemory(len)),
+                }
+                base
+            }
+        };
+        self.allocations += 1;
+        self.allocated_since_gc = self.allocated_since_gc.saturating_add(len);
+        for cell in &mut self.cells[base..base + len] {
+            *cell = Value::Address(None);
+        }
+        self.live.insert(base, len);
+        Ok(base)
+    }
+
+    /// Releases the block starting at `address`
+    pub fn free(&mut self, address: usize) -> Result<(), HeapError> {
+        let len = match self.live.remove(&address) {
+            Some(len) => len,
+            None => return Err(match self.check(address) {
+                Err(err @ HeapError::UseAfterFree(_)) => err,
+                _ => HeapError::InvalidFree(address),
+            }),
+        };
+        self.frees += 1;
+        self.release(address, len);
+        Ok(())
+    }
+
+    /// Adds a block to the free list, merging it with adjacent free blocks
+    fn release(&mut self, mut base: usize, mut len: usize) {
+        if let Some((prev, prev_len)) = self.free.range(..base).next_back().map(|(b, l)| (*b, *l)) {
+            if prev + prev_len == base {
+                self.free.remove(&prev);
+                base = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(base + len)) {
+            len += next_len;
+        }
+        self.free.insert(base, len);
+    }
+
+    pub fn load(&self, address: usize) -> Result<&Value, HeapError> {
+        self.check(address)?;
+        Ok(&self.cells[address])
+    }
+
+    pub fn store(&mut self, address: usize, value: Value) -> Result<(), HeapError> {
+        self.check(address)?;
+        self.cells[address] = value;
+        Ok(())
+    }
+
+    /// Ok if `address` lies inside a live block
+    fn check(&self, address: usize) -> Result<(), HeapError> {
+        let inside = |blocks: &BTreeMap<usize, usize>| blocks.range(..=address)
+            .next_back()
+            .is_some_and(|(base, len)| address < base + len);
+        if inside(&self.live) {
+            Ok(())
+        } else if inside(&self.free) {
+            Err(HeapError::UseAfterFree(address))
+        } else {
+            Err(HeapError::OutOfBounds(address))
+        }
+    }
+
+    /// Frees every block not reachable from `roots`, returning the number of
+    /// blocks freed
+    pub fn collect<'a>(&mut self, roots: impl Iterator<Item = &'a Value>) -> usize {
+        let start = Instant::now();
+
+        let mut pending = vec![];
+        for root in roots {
+            addresses(root, &mut pending);
+        }
+        let mut marked = BTreeSet::new();
+        while let Some(address) = pending.pop() {
+            let block = self.live.range(..=address)
+                .next_back()
+                .filter(|(base, len)| address < *base + *len)
+                .map(|(base, len)| (*base, *len));
+            if let Some((base, len)) = block {
+                if marked.insert(base) {
+                    for cell in &self.cells[base..base + len] {
+                        addresses(cell, &mut pending);
+                    }
+                }
+            }
+        }
+
+        let garbage: Vec<(usize, usize)> = self.live.iter()
+            .filter(|(base, _)| !marked.contains(*base))
+            .map(|(base, len)| (*base, *len))
+            .collect();
+        for (base, len) in &garbage {
+            self.live.remove(base);
+            self.release(*base, *len);
+            self.gc.bytes_freed += len * mem::size_of::<Value>();
+        }
+
+        let pause = start.elapsed();
+        self.allocated_since_gc = 0;
+        self.gc.collections += 1;
+        self.gc.blocks_freed += garbage.len();
+        self.gc.total_pause += pause;
+        self.gc.last_pause = pause;
+        garbage.len()
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        &self.gc
+    }
+
+    /// Live blocks as (base address, cells) in address order
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, &[Value])> {
+        self.live.iter().map(|(base, len)| (*base, &self.cells[*base..base + len]))
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            allocations: self.allocations,
+            frees: self.frees,
+            live_blocks: self.live.len(),
+            live_cells: self.live.values().sum(),
+            capacity: self.cells.len(),
+        }
+    }
+}
+
+/// Collects the heap addresses held by a value. Nested values are walked
+/// with an explicit stack, as guest programs can nest them arbitrarily deep.
+fn addresses(value: &Value, out: &mut Vec<usize>) {
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match value {
+            Value::Address(Some(address)) => out.push(*address),
+            Value::Symbol(inner) => pending.push(inner),
+            Value::Array(items) | Value::Tuple(items) => pending.extend(items),
+            Value::Map(entries) => {
+                for (key, value) in entries {
+                    pending.push(key);
+                    pending.push(value);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_blocks_are_reused_and_merged() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(2).unwrap();
+        let b = heap.alloc(3).unwrap();
+        let c = heap.alloc(1).unwrap();
+        assert_eq!((0, 2, 5), (a, b, c));
+
+        heap.free(a).unwrap();
+        heap.free(b).unwrap();
+        assert_eq!(Err(HeapError::UseAfterFree(3)), heap.load(3));
+        assert_eq!(Err(HeapError::UseAfterFree(0)), heap.free(a));
+        assert_eq!(Err(HeapError::InvalidFree(6)), heap.free(6));
+
+        // the merged five cell hole fits a four cell block
+        assert_eq!(0, heap.alloc(4).unwrap());
+        assert_eq!(4, heap.alloc(1).unwrap());
+        assert_eq!(6, heap.stats().capacity);
+        assert_eq!(Err(HeapError::OutOfBounds(6)), heap.store(6, Value::I32(1)));
+    }
+
+    #[test]
+    fn collection_keeps_reachable_blocks() {
+        let mut heap = Heap::new();
+        let root = heap.alloc(1).unwrap();
+        let child = heap.alloc(2).unwrap();
+        let garbage = heap.alloc(3).unwrap();
+        let cycle = heap.alloc(1).unwrap();
+        heap.store(root, Value::Address(Some(child + 1))).unwrap();
+        heap.store(cycle, Value::Address(Some(cycle))).unwrap();
+        heap.store(garbage, Value::Address(Some(child))).unwrap();
+
+        let roots = [Value::I32(1), Value::Address(Some(root))];
+        assert_eq!(2, heap.collect(roots.iter()));
+
+        assert_eq!(vec![root, child], heap.blocks().map(|(base, _)| base).collect::<Vec<_>>());
+        assert_eq!(Err(HeapError::UseAfterFree(garbage)), heap.load(garbage));
+        let stats = heap.gc_stats();
+        assert_eq!((1, 2, 4 * mem::size_of::<Value>()), (stats.collections, stats.blocks_freed, stats.bytes_freed));
+    }
+
+    #[test]
+    fn collection_walks_deeply_nested_roots() {
+        let mut heap = Heap::new();
+        let block = heap.alloc(1).unwrap();
+        let mut root = Value::Address(Some(block));
+        for _ in 0..100_000 {
+            root = Value::Array(vec![Value::Map(vec![(Value::I32(0), root)])]);
+        }
+        assert_eq!(0, heap.collect(std::iter::once(&root)));
+
+        // dropping recurses as well, so take the nesting apart one level at a time
+        while let Value::Array(mut items) = root {
+            root = match items.pop() {
+                Some(Value::Map(mut entries)) => entries.pop().unwrap().1,
+                _ => unreachable!(),
+            };
+        }
+    }
+
+    #[test]
+    fn allocations_past_the_limit_fail() {
+        let mut heap = Heap::new();
+        heap.set_max_cells(8);
+        assert_eq!(0, heap.alloc(6).unwrap());
+        assert_eq!(Err(HeapError::OutOfMemory(3)), heap.alloc(3));
+        assert_eq!(Err(HeapError::OutOfMemory(usize::MAX)), heap.alloc(usize::MAX));
+        assert_eq!(6, heap.alloc(2).unwrap());
+        assert_eq!(2, heap.stats().allocations);
+    }
+}
@@ -0,0 +1,632 @@
+//! On-disk program images
+//!
+//! All integers are little endian and strings are a u32 byte length
+//! followed by UTF-8.
+//!
+//! ```text
+//! header     "RVMI", u16 version
+//! constants  u32 count, tagged values
+//! code       u32 count, memory cells; value operands index the constant pool
+//! symbols    u32 count, (string name, u64 address)
+//! metadata   u32 count, strings referenced by MetaData cells
+//! ```
+//!
+//! The version is bumped whenever the encoding grows, so that an older
+//! reader rejects a newer image as unsupported rather than as corrupt.
+//! Each version only adds to the last one and all of them are loaded, but
+//! an image may not use opcodes or value tags newer than its version.
+//!
+//! - 1: opcodes 0-44, value tags 0-8
+//! - 2: opcodes up to 112, value tags up to 18
+
+use std::{
+    collections::HashMap,
+    rc::Rc,
+};
+
+use super::error::ImageError;
+use super::types::ValueType;
+use super::vm::{instruction_table, Instruction, MemoryCell, Message, MetaData, RustyVM, Value};
+
+
+pub const IMAGE_MAGIC: &[u8; 4] = b"RVMI";
+pub const IMAGE_VERSION: u16 = 2;
+
+/// Highest opcode and value tag that each image version, from 1, may contain
+const VERSION_LIMITS: [(u16, u8); IMAGE_VERSION as usize] = [(44, TAG_ADDRESS), (112, TAG_BYTES)];
+
+/// Deepest nesting of symbols and compound values an image may contain,
+/// as decoding recurses once per level
+pub const MAX_VALUE_DEPTH: usize = 256;
+
+// value tags
+const TAG_I32: u8 = 0;
+const TAG_I64: u8 = 1;
+const TAG_F32: u8 = 2;
+const TAG_F64: u8 = 3;
+const TAG_CHAR: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_BOOL: u8 = 6;
+const TAG_SYMBOL: u8 = 7;
+const TAG_ADDRESS: u8 = 8;
+const TAG_ARRAY: u8 = 9;
+const TAG_TUPLE: u8 = 10;
+const TAG_MAP: u8 = 11;
+const TAG_I8: u8 = 12;
+const TAG_I16: u8 = 13;
+const TAG_U8: u8 = 14;
+const TAG_U16: u8 = 15;
+const TAG_U32: u8 = 16;
+const TAG_U64: u8 = 17;
+const TAG_BYTES: u8 = 18;
+
+// memory cell kinds
+const CELL_INSTRUCTION: u8 = 0;
+const CELL_VALUE: u8 = 1;
+const CELL_METADATA: u8 = 2;
+const CELL_EMPTY: u8 = 3;
+
+impl RustyVM {
+    /// Serializes the program memory and symbols into an image. Fails if
+    /// a length or count does not fit the format's u32 fields.
+    pub fn save_image(&self) -> Result<Vec<u8>, ImageError> {
+        let mut writer = Writer::default();
+        let mut code = vec![];
+        for cell in self.memory() {
+            writer.cell(&mut code, cell)?;
+        }
+
+        let mut image = vec![];
+        image.extend_from_slice(IMAGE_MAGIC);
+        put_u16(&mut image, IMAGE_VERSION);
+        put_len(&mut image, writer.constants.len())?;
+        for value in &writer.constants {
+            put_value(&mut image, value)?;
+        }
+        put_len(&mut image, self.memory().len())?;
+        image.extend_from_slice(&code);
+        put_len(&mut image, self.symbols().len())?;
+        for (name, address) in self.symbols() {
+            put_str(&mut image, name)?;
+            put_u64(&mut image, *address as u64);
+        }
+        put_len(&mut image, writer.metadata.len())?;
+        for tag in &writer.metadata {
+            put_str(&mut image, tag)?;
+        }
+        Ok(image)
+    }
+
+    /// Rebuilds a VM from an image produced by `save_image`. The VM is
+    /// returned stopped; call `reset` before running it.
+    pub fn load_image(bytes: &[u8]) -> Result<RustyVM, ImageError> {
+        let mut reader = Reader { bytes, pos: 0, depth: 0, max_opcode: 0, max_tag: 0 };
+
+        if reader.take(4).map_err(|_| ImageError::BadMagic)? != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if !(1..=IMAGE_VERSION).contains(&version) {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+        (reader.max_opcode, reader.max_tag) = VERSION_LIMITS[version as usize - 1];
+
+        let count = reader.u32()?;
+        let mut constants = vec![];
+        for _ in 0..count {
+            constants.push(reader.value()?);
+        }
+
+        // metadata follows the code it is referenced from, so cells are
+        // decoded first and their metadata indexes checked afterwards
+        let count = reader.u32()?;
+        let mut cells = vec![];
+        for _ in 0..count {
+            cells.push(reader.cell(&constants)?);
+        }
+
+        let count = reader.u32()?;
+        let mut symbols = vec![];
+        for _ in 0..count {
+            let name = reader.string()?;
+            let address = reader.u64()?;
+            if address > cells.len() as u64 {
+                return Err(ImageError::SymbolOutOfRange(name, address));
+            }
+            symbols.push((name, address as usize));
+        }
+
+        let count = reader.u32()?;
+        let mut metadata = vec![];
+        for _ in 0..count {
+            metadata.push(reader.string()?);
+        }
+        if reader.pos != bytes.len() {
+            return Err(ImageError::TrailingBytes(reader.pos));
+        }
+
+        let mut vm = RustyVM::new();
+        for cell in cells {
+            vm.push(match cell {
+                Cell::Memory(cell) => cell,
+                Cell::MetaData(offset, index) => match metadata.get(index as usize) {
+                    Some(tag) => MemoryCell::MetaData(MetaData::Tag(tag.clone())),
+                    None => return Err(ImageError::MetadataOutOfRange(offset, index)),
+                }
+            });
+        }
+        for (name, address) in symbols {
+            vm.add_symbol(&name, address);
+        }
+        Ok(vm)
+    }
+}
+
+/// Collects the constant pool and metadata strings while encoding code
+#[derive(Default)]
+struct Writer {
+    constants: Vec<Value>,
+    interned: HashMap<Vec<u8>, usize>,
+    metadata: Vec<String>,
+}
+
+impl Writer {
+    /// Adds `value` to the constant pool, reusing an identical entry
+    fn constant(&mut self, out: &mut Vec<u8>, value: &Value) -> Result<(), ImageError> {
+        let mut key = vec![];
+        put_value(&mut key, value)?;
+        let next = self.constants.len();
+        let index = *self.interned.entry(key).or_insert(next);
+        if index == next {
+            self.constants.push(value.clone());
+        }
+        put_len(out, index)
+    }
+
+    fn cell(&mut self, out: &mut Vec<u8>, cell: &MemoryCell) -> Result<(), ImageError> {
+        match cell {
+            MemoryCell::Instruction(inst) => {
+                out.push(CELL_INSTRUCTION);
+                self.instruction(out, inst)?;
+            },
+            MemoryCell::Value(value) => {
+                out.push(CELL_VALUE);
+                self.constant(out, value)?;
+            },
+            MemoryCell::MetaData(MetaData::Tag(tag)) => {
+                out.push(CELL_METADATA);
+                put_len(out, self.metadata.len())?;
+                self.metadata.push(tag.clone());
+            },
+            MemoryCell::Empty => out.push(CELL_EMPTY),
+        }
+        Ok(())
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, n: u16) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Writes a length, count or index, which the format stores as a u32
+fn put_len(out: &mut Vec<u8>, len: usize) -> Result<(), ImageError> {
+    put_u32(out, u32::try_from(len).map_err(|_| ImageError::TooLarge(len))?);
+    Ok(())
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) -> Result<(), ImageError> {
+    put_len(out, s.len())?;
+    out.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn put_value(out: &mut Vec<u8>, value: &Value) -> Result<(), ImageError> {
+    match value {
+        Value::I32(n) => {
+            out.push(TAG_I32);
+            out.extend_from_slice(&n.to_le_bytes());
+        },
+        Value::I64(n) => {
+            out.push(TAG_I64);
+            out.extend_from_slice(&n.to_le_bytes());
+        },
+        Value::I8(n) => {
+            out.push(TAG_I8);
+            out.extend_from_slice(&n.to_le_bytes());
+        },
+        Value::I16(n) => {
+            out.push(TAG_I16);
+            out.extend_from_slice(&n.to_le_bytes());
+        },
+        Value::U8(n) => {
+            out.push(TAG_U8);
+            out.push(*n);
+        },
+        Value::U16(n) => {
+            out.push(TAG_U16);
+            out.extend_from_slice(&n.to_le_bytes());
+        },
+        Value::U32(n) => {
+            out.push(TAG_U32);
+            put_u32(out, *n);
+        },
+        Value::U64(n) => {
+            out.push(TAG_U64);
+            put_u64(out, *n);
+        },
+        Value::F32(n) => {
+            out.push(TAG_F32);
+            out.extend_from_slice(&n.to_bits().to_le_bytes());
+        },
+        Value::F64(n) => {
+            out.push(TAG_F64);
+            out.extend_from_slice(&n.to_bits().to_le_bytes());
+        },
+        Value::Char(c) => {
+            out.push(TAG_CHAR);
+            put_u32(out, *c as u32);
+        },
+        Value::String(s) => {
+            out.push(TAG_STRING);
+            put_str(out, s)?;
+        },
+        Value::Bytes(bytes) => {
+            out.push(TAG_BYTES);
+            put_len(out, bytes.len())?;
+            out.extend_from_slice(bytes);
+        },
+        Value::Bool(b) => {
+            out.push(TAG_BOOL);
+            out.push(*b as u8);
+        },
+        Value::Symbol(inner) => {
+            out.push(TAG_SYMBOL);
+            put_value(out, inner)?;
+        },
+        Value::Address(address) => {
+            out.push(TAG_ADDRESS);
+            match address {
+                Some(address) => {
+                    out.push(1);
+                    put_u64(out, *address as u64);
+                },
+                None => out.push(0),
+            }
+        },
+        Value::Array(items) => {
+            out.push(TAG_ARRAY);
+            put_items(out, items)?;
+        },
+        Value::Tuple(items) => {
+            out.push(TAG_TUPLE);
+            put_items(out, items)?;
+        },
+        Value::Map(entries) => {
+            out.push(TAG_MAP);
+            put_len(out, entries.len())?;
+            for (key, value) in entries {
+                put_value(out, key)?;
+                put_value(out, value)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+fn put_items(out: &mut Vec<u8>, items: &[Value]) -> Result<(), ImageError> {
+    put_len(out, items.len())?;
+    for item in items {
+        put_value(out, item)?;
+    }
+    Ok(())
+}
+
+/// A decoded memory cell whose metadata string is not known yet
+enum Cell {
+    Memory(MemoryCell),
+    MetaData(usize, u32),       // offset, metadata index
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,       // values being decoded, counting the current one
+    max_opcode: u16,    // the newest opcode and tag the image's version has
+    max_tag: u8,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ImageError> {
+        match self.bytes.get(self.pos..self.pos + n) {
+            Some(slice) => {
+                self.pos += n;
+                Ok(slice)
+            },
+            None => Err(ImageError::Truncated(self.pos)),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, ImageError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ImageError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ImageError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ImageError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, ImageError> {
+        let offset = self.pos;
+        let n = self.u64()?;
+        usize::try_from(n).map_err(|_| ImageError::Overflow(offset, n))
+    }
+
+    fn string(&mut self) -> Result<String, ImageError> {
+        let len = self.u32()? as usize;
+        let offset = self.pos;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| ImageError::InvalidUtf8(offset))
+    }
+
+    fn value(&mut self) -> Result<Value, ImageError> {
+        if self.depth == MAX_VALUE_DEPTH {
+            return Err(ImageError::TooDeep(self.pos));
+        }
+        self.depth += 1;
+        let value = self.tagged_value();
+        self.depth -= 1;
+        value
+    }
+
+    fn tagged_value(&mut self) -> Result<Value, ImageError> {
+        let offset = self.pos;
+        match self.u8()? {
+            tag if tag > self.max_tag => Err(ImageError::InvalidValueTag(offset, tag)),
+            TAG_I32 => Ok(Value::I32(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))),
+            TAG_I64 => Ok(Value::I64(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))),
+            TAG_I8 => Ok(Value::I8(self.u8()? as i8)),
+            TAG_I16 => Ok(Value::I16(i16::from_le_bytes(self.take(2)?.try_into().unwrap()))),
+            TAG_U8 => Ok(Value::U8(self.u8()?)),
+            TAG_U16 => Ok(Value::U16(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))),
+            TAG_U32 => Ok(Value::U32(self.u32()?)),
+            TAG_U64 => Ok(Value::U64(self.u64()?)),
+            TAG_F32 => Ok(Value::F32(f32::from_bits(self.u32()?))),
+            TAG_F64 => Ok(Value::F64(f64::from_bits(self.u64()?))),
+            TAG_CHAR => {
+                let offset = self.pos;
+                let code = self.u32()?;
+                char::from_u32(code).map(Value::Char).ok_or(ImageError::InvalidChar(offset, code))
+            },
+            TAG_STRING => Ok(Value::String(self.string()?)),
+            TAG_BYTES => {
+                let len = self.u32()? as usize;
+                Ok(Value::Bytes(self.take(len)?.to_vec()))
+            },
+            TAG_BOOL => match self.u8()? {
+                0 => Ok(Value::Bool(false)),
+                1 => Ok(Value::Bool(true)),
+                tag => Err(ImageError::InvalidValueTag(offset, tag)),
+            },
+            TAG_SYMBOL => Ok(Value::Symbol(Rc::new(self.value()?))),
+            TAG_ADDRESS => match self.u8()? {
+                0 => Ok(Value::Address(None)),
+                1 => Ok(Value::Address(Some(self.usize()?))),
+                tag => Err(ImageError::InvalidValueTag(offset, tag)),
+            },
+            TAG_ARRAY => Ok(Value::Array(self.items()?)),
+            TAG_TUPLE => Ok(Value::Tuple(self.items()?)),
+            TAG_MAP => {
+                let count = self.u32()?;
+                // not preallocated, the count is untrusted
+                let mut entries = vec![];
+                for _ in 0..count {
+                    entries.push((self.value()?, self.value()?));
+                }
+                Ok(Value::Map(entries))
+            },
+            tag => Err(ImageError::InvalidValueTag(offset, tag)),
+        }
+    }
+
+    fn items(&mut self) -> Result<Vec<Value>, ImageError> {
+        let count = self.u32()?;
+        let mut items = vec![];
+        for _ in 0..count {
+            items.push(self.value()?);
+        }
+        Ok(items)
+    }
+
+    fn constant(&mut self, constants: &[Value]) -> Result<Value, ImageError> {
+        let offset = self.pos;
+        let index = self.u32()?;
+        constants.get(index as usize).cloned().ok_or(ImageError::ConstantOutOfRange(offset, index))
+    }
+
+    fn cell(&mut self, constants: &[Value]) -> Result<Cell, ImageError> {
+        let offset = self.pos;
+        match self.u8()? {
+            CELL_INSTRUCTION => Ok(Cell::Memory(MemoryCell::Instruction(self.instruction(constants)?))),
+            CELL_VALUE => Ok(Cell::Memory(MemoryCell::Value(self.constant(constants)?))),
+            CELL_METADATA => {
+                let offset = self.pos;
+                Ok(Cell::MetaData(offset, self.u32()?))
+            },
+            CELL_EMPTY => Ok(Cell::Memory(MemoryCell::Empty)),
+            kind => Err(ImageError::InvalidCellKind(offset, kind)),
+        }
+    }
+}
+
+/// Generates the encoding of every instruction from the instruction table:
+/// its opcode followed by its operands in field order. Registers and counts
+/// are u64s, values and jump targets constant pool indexes, a message its
+/// sender and receiver followed by its value, and a type its index.
+macro_rules! image_codec {
+    (|$vm:ident| $( $name:ident = $opcode:literal $mnemonic:literal $( ( $( $arg:ident : $kind:ident ),* ) )? => $body:expr, )*) => {
+        impl Writer {
+            fn instruction(&mut self, out: &mut Vec<u8>, inst: &Instruction) -> Result<(), ImageError> {
+                match inst {
+                    $( Instruction::$name $( ( $( $arg ),* ) )? => {
+                        put_u16(out, $opcode);
+                        $( $( encode!($kind, self, out, $arg); )* )?
+                    } )*
+                }
+                Ok(())
+            }
+        }
+
+        impl<'a> Reader<'a> {
+            fn instruction(&mut self, constants: &[Value]) -> Result<Instruction, ImageError> {
+                let offset = self.pos;
+                let inst = match self.u16()? {
+                    opcode if opcode > self.max_opcode => return Err(ImageError::InvalidOpcode(offset, opcode)),
+                    $( $opcode => Instruction::$name $( ( $( decode!($kind, self, constants) ),* ) )?, )*
+                    opcode => return Err(ImageError::InvalidOpcode(offset, opcode)),
+                };
+                Ok(inst)
+            }
+        }
+    };
+}
+
+macro_rules! encode {
+    (constant, $writer:ident, $out:ident, $arg:ident) => { $writer.constant($out, $arg)? };
+    (target, $writer:ident, $out:ident, $arg:ident) => { $writer.constant($out, $arg)? };
+    (message, $writer:ident, $out:ident, $arg:ident) => {{
+        put_u64($out, $arg.from as u64);
+        put_u64($out, $arg.to as u64);
+        $writer.constant($out, &$arg.value)?;
+    }};
+    (value_type, $writer:ident, $out:ident, $arg:ident) => { put_u64($out, $arg.index() as u64) };
+    // registers and counts
+    ($kind:ident, $writer:ident, $out:ident, $arg:ident) => { put_u64($out, *$arg as u64) };
+}
+
+macro_rules! decode {
+    (constant, $reader:ident, $constants:ident) => { $reader.constant($constants)? };
+    (target, $reader:ident, $constants:ident) => { $reader.constant($constants)? };
+    (message, $reader:ident, $constants:ident) => {{
+        let from = $reader.usize()?;
+        let to = $reader.usize()?;
+        Message { from, to, value: $reader.constant($constants)? }
+    }};
+    (value_type, $reader:ident, $constants:ident) => {{
+        let offset = $reader.pos;
+        let index = $reader.usize()?;
+        match ValueType::from_index(index) {
+            Some(target) => target,
+            None => return Err(ImageError::InvalidType(offset, index)),
+        }
+    }};
+    ($kind:ident, $reader:ident, $constants:ident) => { $reader.usize()? };
+}
+
+instruction_table!(image_codec);
+
+
+#[cfg(test)]
+mod tests {
+    use crate::rvm::{builder::VMBuilder, error::ImageError, types::ValueType, vm::*};
+    use super::{put_len, IMAGE_VERSION, MAX_VALUE_DEPTH, TAG_ARRAY, TAG_SYMBOL, TAG_U8};
+
+    #[test]
+    fn image_round_trips_program_and_symbols() {
+        let mut builder = VMBuilder::new();
+        builder
+            .label("Start")
+            .push(Value::String(String::from("hello")))
+            .push(Value::F64(f64::NAN))
+            .ld_imm(3, Value::Char('λ'))
+            .call("Sub", 1)
+            .out(1, Message { from: 2, to: 3, value: Value::Symbol(std::rc::Rc::new(Value::I64(-7))) })
+            .halt()
+            .label("Sub")
+            .ret()
+            .push(Value::Map(vec![(Value::Tuple(vec![]), Value::Array(vec![Value::Bool(true)]))]))
+            .build()
+            .unwrap();
+        let vm = builder.vm();
+        let image = vm.save_image().unwrap();
+        let loaded = RustyVM::load_image(&image).unwrap();
+
+        // NaN never compares equal, so compare the re-encoded images instead
+        assert_eq!(image, loaded.save_image().unwrap());
+        assert_eq!(vm.symbols(), loaded.symbols());
+        assert_eq!(vm.memory().len(), loaded.memory().len());
+        assert_eq!(vm.memory()[3], loaded.memory()[3]);
+        assert_eq!(vm.memory()[7], loaded.memory()[7]);
+    }
+
+    #[test]
+    fn malformed_images_are_rejected() {
+        let mut builder = VMBuilder::new();
+        builder.push(Value::I32(1)).halt().build().unwrap();
+        let image = builder.vm().save_image().unwrap();
+
+        assert_eq!(Some(ImageError::BadMagic), RustyVM::load_image(b"ELF!").err());
+        let mut newer = image.clone();
+        newer[4..6].copy_from_slice(&(IMAGE_VERSION + 1).to_le_bytes());
+        assert_eq!(Some(ImageError::UnsupportedVersion(IMAGE_VERSION + 1)), RustyVM::load_image(&newer).err());
+        let mut older = image.clone();
+        older[4..6].copy_from_slice(&1u16.to_le_bytes());
+        assert!(RustyVM::load_image(&older).is_ok());
+        // the metadata count is the last field
+        assert_eq!(Some(ImageError::Truncated(image.len() - 4)), RustyVM::load_image(&image[..image.len() - 1]).err());
+
+        let mut extended = image.clone();
+        extended.push(0);
+        assert_eq!(Some(ImageError::TrailingBytes(image.len())), RustyVM::load_image(&extended).err());
+
+        // header (6) + constant count (4) + I32 constant (5) + cell count (4) + cell kind (1)
+        let mut bad_opcode = image.clone();
+        bad_opcode[20] = 0xff;
+        assert_eq!(Some(ImageError::InvalidOpcode(20, 0xff)), RustyVM::load_image(&bad_opcode).err());
+
+        // header (6) + constant count (4) + cell count (4) + cell kind (1) + opcode (2)
+        let mut conv = VMBuilder::new();
+        conv.conv(ValueType::I32).halt().build().unwrap();
+        let mut bad_type = conv.vm().save_image().unwrap();
+        bad_type[17..19].copy_from_slice(&256u16.to_le_bytes());
+        assert_eq!(Some(ImageError::InvalidType(17, 256)), RustyVM::load_image(&bad_type).err());
+
+        // conv and u8 constants only exist from version 2 on
+        let mut v1_opcode = conv.vm().save_image().unwrap();
+        v1_opcode[4..6].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(Some(ImageError::InvalidOpcode(15, 73)), RustyVM::load_image(&v1_opcode).err());
+        let mut u8_constant = VMBuilder::new();
+        u8_constant.push(Value::U8(1)).halt().build().unwrap();
+        let mut v1_tag = u8_constant.vm().save_image().unwrap();
+        v1_tag[4..6].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(Some(ImageError::InvalidValueTag(10, TAG_U8)), RustyVM::load_image(&v1_tag).err());
+
+        // lengths beyond a u32 are refused rather than truncated when saving
+        assert_eq!(Err(ImageError::TooLarge(1 << 32)), put_len(&mut vec![], 1 << 32));
+
+        // a constant of symbols nested far deeper than the decoder allows
+        let mut deep = image[..10].to_vec();
+        deep.extend(std::iter::repeat_n(TAG_SYMBOL, 2_000_000));
+        assert_eq!(Some(ImageError::TooDeep(10 + MAX_VALUE_DEPTH)), RustyVM::load_image(&deep).err());
+
+        // and of single element arrays, each a tag and a u32 count
+        let mut deep = image[..10].to_vec();
+        for _ in 0..1_000_000 {
+            deep.extend_from_slice(&[TAG_ARRAY, 1, 0, 0, 0]);
+        }
+        assert_eq!(Some(ImageError::TooDeep(10 + 5 * MAX_VALUE_DEPTH)), RustyVM::load_image(&deep).err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Lowered programs
+//!
+//! `RustyVM::run` fetches every instruction by cloning it out of memory,
+//! payload and all. Lowering turns the memory of a built program into
+//! fixed-width ops whose operands are registers, counts, ports and jump
+//! targets, or indexes into pools holding the values and messages, so that
+//! `RustyVM::run_lowered` can dispatch without cloning anything but the
+//! values it pushes.
+
+use super::vm::{instruction_table, Instruction, MemoryCell, Message, Value};
+
+
+/// Generates `Opcode` and the lowering of every instruction from the
+/// instruction table
+macro_rules! lowering {
+    (|$vm:ident| $( $name:ident = $opcode:literal $mnemonic:literal $( ( $( $arg:ident : $kind:ident ),* ) )? => $body:expr, )*) => {
+        /// Operation codes of lowered instructions, one for each `Instruction`
+        /// variant plus the cells that cannot run as they are. An op holds the
+        /// operands of its instruction in field order in `a`, `b` and `c`:
+        /// registers, counts, ports and jump targets as they are, values and
+        /// messages as pool indexes and a `ValueType` as its index.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum Opcode {
+            $( $name, )*
+            Unresolved,             // a branch whose label was never resolved
+            Invalid,                // a memory cell that holds no instruction
+            Fallback,               // a: original instruction, run when an operand is too wide for an op
+        }
+
+        impl Program {
+            /// Lowers an instruction, or returns `None` if an operand does not fit in an op
+            fn instruction(&mut self, inst: &Instruction) -> Option<Op> {
+                let op = match inst {
+                    $( Instruction::$name $( ( $( $arg ),* ) )? => {
+                        Op::with_operands(Opcode::$name, &[$( $( lower!($kind, self, $arg) ),* )?])
+                    } )*
+                };
+                Some(op)
+            }
+        }
+    };
+}
+
+/// Lowers an operand of an instruction, which `instruction` matched by reference
+macro_rules! lower {
+    (register, $program:ident, $arg:ident) => { operand(*$arg)? };
+    (count, $program:ident, $arg:ident) => { operand(*$arg)? };
+    (argc, $program:ident, $arg:ident) => { operand(*$arg)? };
+    (constant, $program:ident, $arg:ident) => { $program.constant($arg) };
+    (message, $program:ident, $arg:ident) => { $program.message($arg) };
+    (value_type, $program:ident, $arg:ident) => { $arg.index() as u32 };
+    // a jump or call only runs once the builder resolved its target
+    (target, $program:ident, $arg:ident) => {
+        match $arg {
+            Value::Address(Some(address)) => operand(*address)?,
+            _ => return Some(Op::new(Opcode::Unresolved, 0, 0, 0)),
+        }
+    };
+}
+
+instruction_table!(lowering);
+
+/// A lowered instruction. Operands an op does not use are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Op {
+    pub opcode: Opcode,
+    pub a: u32,
+    pub b: u32,
+    pub c: u32,
+}
+
+impl Op {
+    pub fn new(opcode: Opcode, a: u32, b: u32, c: u32) -> Op {
+        Op { opcode, a, b, c }
+    }
+
+    /// Builds an op from the operands an instruction uses, in field order
+    fn with_operands(opcode: Opcode, operands: &[u32]) -> Op {
+        let operand = |i: usize| operands.get(i).copied().unwrap_or(0);
+        Op::new(opcode, operand(0), operand(1), operand(2))
+    }
+}
+
+/// A program lowered from VM memory, with one op per memory cell so that
+/// addresses and the pc are unchanged. It is only valid for the memory it
+/// was lowered from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    code: Vec<Op>,
+    constants: Vec<Value>,
+    messages: Vec<Message>,
+    fallback: Vec<Instruction>,
+}
+
+impl Program {
+    pub fn lower(memory: &[MemoryCell]) -> Program {
+        let mut program = Program::default();
+        for cell in memory {
+            let op = match cell {
+                MemoryCell::Instruction(inst) => match program.instruction(inst) {
+                    Some(op) => op,
+                    None => {
+                        program.fallback.push(inst.clone());
+                        Op::new(Opcode::Fallback, program.fallback.len() as u32 - 1, 0, 0)
+                    }
+                },
+                _ => Op::new(Opcode::Invalid, 0, 0, 0),
+            };
+            program.code.push(op);
+        }
+        program
+    }
+
+    pub fn code(&self) -> &[Op] {
+        &self.code
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub(super) fn constant_at(&self, index: u32) -> &Value {
+        &self.constants[index as usize]
+    }
+
+    pub(super) fn message_at(&self, index: u32) -> &Message {
+        &self.messages[index as usize]
+    }
+
+    pub(super) fn fallback_at(&self, index: u32) -> &Instruction {
+        &self.fallback[index as usize]
+    }
+
+    fn constant(&mut self, value: &Value) -> u32 {
+        self.constants.push(value.clone());
+        self.constants.len() as u32 - 1
+    }
+
+    fn message(&mut self, message: &Message) -> u32 {
+        self.messages.push(message.clone());
+        self.messages.len() as u32 - 1
+    }
+}
+
+fn operand(n: usize) -> Option<u32> {
+    u32::try_from(n).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_move_into_pools() {
+        let message = Message { from: 0, to: 1, value: Value::Char('x') };
+        let memory = vec![
+            MemoryCell::Instruction(Instruction::Push(Value::String("hi".to_string()))),
+            MemoryCell::Instruction(Instruction::LdImm(3, Value::F64(0.5))),
+            MemoryCell::Instruction(Instruction::Call(Value::Address(Some(7)), 2)),
+            MemoryCell::Instruction(Instruction::Jz(Value::String("Label".to_string()))),
+            MemoryCell::Instruction(Instruction::Out(1, message.clone())),
+            MemoryCell::Instruction(Instruction::Pick(usize::MAX)),
+            MemoryCell::Value(Value::I32(1)),
+        ];
+        let program = Program::lower(&memory);
+
+        assert_eq!(&[
+            Op::new(Opcode::Push, 0, 0, 0),
+            Op::new(Opcode::LdImm, 3, 1, 0),
+            Op::new(Opcode::Call, 7, 2, 0),
+            Op::new(Opcode::Unresolved, 0, 0, 0),
+            Op::new(Opcode::Out, 1, 0, 0),
+            Op::new(Opcode::Fallback, 0, 0, 0),
+            Op::new(Opcode::Invalid, 0, 0, 0),
+        ], program.code());
+        assert_eq!(&[Value::String("hi".to_string()), Value::F64(0.5)], program.constants());
+        assert_eq!(&message, program.message_at(0));
+        assert_eq!(&Instruction::Pick(usize::MAX), program.fallback_at(0));
+    }
+}
@@ -0,0 +1,75 @@
+//! I/O ports connecting a VM to devices supplied by the host
+//!
+//! Every `RustyVM` owns its own port table, so VMs in the same process can
+//! be wired to different devices. Clones of a VM share the attached devices.
+//! Devices are held in `Rc<RefCell<_>>`, which keeps a VM on the thread it
+//! was created on; they need not be `Send`.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    rc::Rc,
+};
+
+use super::vm::Message;
+
+
+/// A device attached to a VM port
+pub trait Port {
+    /// Delivers a message written by the program. An error raises a VM exception.
+    fn send(&mut self, message: Message) -> Result<(), String>;
+
+    /// Returns the next message for the program, if one is available
+    fn receive(&mut self) -> Option<Message> {
+        None
+    }
+}
+
+/// A device made of two closures
+pub struct MessageHandler {
+    pub sender: Box<dyn FnMut(Message)>,
+    pub receiver: Box<dyn FnMut() -> Option<Message>>,
+}
+
+impl Port for MessageHandler {
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        (self.sender)(message);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        (self.receiver)()
+    }
+}
+
+/// Devices by port number
+#[derive(Clone, Default)]
+pub struct PortTable {
+    ports: BTreeMap<usize, Rc<RefCell<dyn Port>>>,
+}
+
+impl PortTable {
+    pub fn new() -> PortTable {
+        PortTable::default()
+    }
+
+    /// Attaches `device` to `port`, replacing any device already there
+    pub fn attach(&mut self, port: usize, device: impl Port + 'static) {
+        self.ports.insert(port, Rc::new(RefCell::new(device)));
+    }
+
+    pub fn detach(&mut self, port: usize) -> bool {
+        self.ports.remove(&port).is_some()
+    }
+
+    pub fn get(&self, port: usize) -> Option<&Rc<RefCell<dyn Port>>> {
+        self.ports.get(&port)
+    }
+}
+
+impl fmt::Debug for PortTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.ports.keys()).finish()
+    }
+}
@@ -0,0 +1,261 @@
+//! Value types, as used by the Conv and TypeOf instructions
+
+use std::fmt;
+
+use super::vm::Value;
+
+
+/// The type of a `Value`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    I32,
+    I64,
+    F32,
+    F64,
+    Char,
+    Bool,
+    String,
+    Symbol,
+    Address,
+    Array,
+    Tuple,
+    Map,
+    I8,
+    I16,
+    U8,
+    U16,
+    U32,
+    U64,
+    Bytes,
+}
+
+/// Names in image order; new types are only ever appended
+const TYPES: [(ValueType, &str); 19] = [
+    (ValueType::I32, "i32"),
+    (ValueType::I64, "i64"),
+    (ValueType::F32, "f32"),
+    (ValueType::F64, "f64"),
+    (ValueType::Char, "char"),
+    (ValueType::Bool, "bool"),
+    (ValueType::String, "string"),
+    (ValueType::Symbol, "symbol"),
+    (ValueType::Address, "address"),
+    (ValueType::Array, "array"),
+    (ValueType::Tuple, "tuple"),
+    (ValueType::Map, "map"),
+    (ValueType::I8, "i8"),
+    (ValueType::I16, "i16"),
+    (ValueType::U8, "u8"),
+    (ValueType::U16, "u16"),
+    (ValueType::U32, "u32"),
+    (ValueType::U64, "u64"),
+    (ValueType::Bytes, "bytes"),
+];
+
+impl ValueType {
+    /// Lower case name, as written in assembly and pushed by TypeOf
+    pub fn name(self) -> &'static str {
+        TYPES[self.index()].1
+    }
+
+    pub fn from_name(name: &str) -> Option<ValueType> {
+        TYPES.iter().find(|(_, n)| *n == name).map(|(t, _)| *t)
+    }
+
+    /// Stable number used in program images
+    pub fn index(self) -> usize {
+        TYPES.iter().position(|(t, _)| *t == self).unwrap_or_default()
+    }
+
+    pub fn from_index(index: usize) -> Option<ValueType> {
+        TYPES.get(index).map(|(t, _)| *t)
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Why a conversion failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConvError {
+    Overflow,           // the value does not fit the target type
+    Invalid(String),    // the value has no representation in the target type
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::I8(_) => ValueType::I8,
+            Value::I16(_) => ValueType::I16,
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::U8(_) => ValueType::U8,
+            Value::U16(_) => ValueType::U16,
+            Value::U32(_) => ValueType::U32,
+            Value::U64(_) => ValueType::U64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+            Value::Char(_) => ValueType::Char,
+            Value::Bool(_) => ValueType::Bool,
+            Value::String(_) => ValueType::String,
+            Value::Bytes(_) => ValueType::Bytes,
+            Value::Symbol(_) => ValueType::Symbol,
+            Value::Address(_) => ValueType::Address,
+            Value::Array(_) => ValueType::Array,
+            Value::Tuple(_) => ValueType::Tuple,
+            Value::Map(_) => ValueType::Map,
+        }
+    }
+
+    /// Converts between the scalar types, strings and bytes.
+    ///
+    /// Integer narrowing and float to integer conversion are checked, with
+    /// floats truncated toward zero. Chars convert through their code point
+    /// and bools through 0 and 1. Strings are parsed, and anything converts
+    /// to a string using its `Display` text. Strings and bytes convert
+    /// through UTF-8, and bytes to and from arrays of u8.
+    pub fn convert(&self, target: ValueType) -> Result<Value, ConvError> {
+        if self.value_type() == target {
+            return Ok(self.clone());
+        }
+        match (self, target) {
+            (Value::Bytes(bytes), ValueType::String) => return String::from_utf8(bytes.clone())
+                .map(Value::String)
+                .map_err(|_| ConvError::Invalid(String::from("bytes are not valid UTF-8"))),
+            (Value::Bytes(bytes), ValueType::Array) => return Ok(Value::Array(bytes.iter().map(|b| Value::U8(*b)).collect())),
+            (Value::String(s), ValueType::Bytes) => return Ok(Value::Bytes(s.as_bytes().to_vec())),
+            (Value::Array(items), ValueType::Bytes) => return to_bytes(items),
+            (_, ValueType::String) => return Ok(Value::String(self.to_string())),
+            _ => (),
+        }
+        if let Some(n) = self.as_integer() {
+            return from_integer(n, target);
+        }
+        match self {
+            Value::String(s) => parse(s, target),
+            Value::F32(n) => from_float(*n as f64, target),
+            Value::F64(n) => from_float(*n, target),
+            Value::Char(c) => from_integer(*c as i128, target),
+            Value::Bool(b) => from_integer(*b as i128, target),
+            value => Err(ConvError::Invalid(format!("cannot convert {} to {}", value.value_type(), target))),
+        }
+    }
+}
+
+fn from_integer(n: i128, target: ValueType) -> Result<Value, ConvError> {
+    let overflow = |_| ConvError::Overflow;
+    match target {
+        ValueType::I8 => i8::try_from(n).map(Value::I8).map_err(overflow),
+        ValueType::I16 => i16::try_from(n).map(Value::I16).map_err(overflow),
+        ValueType::I32 => i32::try_from(n).map(Value::I32).map_err(overflow),
+        ValueType::I64 => i64::try_from(n).map(Value::I64).map_err(overflow),
+        ValueType::U8 => u8::try_from(n).map(Value::U8).map_err(overflow),
+        ValueType::U16 => u16::try_from(n).map(Value::U16).map_err(overflow),
+        ValueType::U32 => u32::try_from(n).map(Value::U32).map_err(overflow),
+        ValueType::U64 => u64::try_from(n).map(Value::U64).map_err(overflow),
+        ValueType::F32 => Ok(Value::F32(n as f32)),
+        ValueType::F64 => Ok(Value::F64(n as f64)),
+        ValueType::Bool => Ok(Value::Bool(n != 0)),
+        ValueType::Char => u32::try_from(n)
+            .ok()
+            .and_then(char::from_u32)
+            .map(Value::Char)
+            .ok_or_else(|| ConvError::Invalid(format!("{} is not a valid char", n))),
+        target => Err(ConvError::Invalid(format!("cannot convert a number to {}", target))),
+    }
+}
+
+fn from_float(n: f64, target: ValueType) -> Result<Value, ConvError> {
+    match target {
+        ValueType::F32 if n.is_finite() && n.abs() > f32::MAX as f64 => Err(ConvError::Overflow),
+        ValueType::F32 => Ok(Value::F32(n as f32)),
+        ValueType::F64 => Ok(Value::F64(n)),
+        ValueType::Bool => Ok(Value::Bool(n != 0.0)),
+        // i128 holds every integer the targets can, so only NaN and infinity need checking here
+        _ if !n.is_finite() => Err(ConvError::Overflow),
+        target => from_integer(n.trunc() as i128, target),
+    }
+}
+
+/// Packs an array of integers that each fit in a byte
+fn to_bytes(items: &[Value]) -> Result<Value, ConvError> {
+    items.iter()
+        .map(|item| match item.as_integer() {
+            Some(n) => u8::try_from(n).map_err(|_| ConvError::Overflow),
+            None => Err(ConvError::Invalid(format!("{:?} is not a byte", item))),
+        })
+        .collect::<Result<Vec<u8>, ConvError>>()
+        .map(Value::Bytes)
+}
+
+fn parse(s: &str, target: ValueType) -> Result<Value, ConvError> {
+    let invalid = || ConvError::Invalid(format!("{:?} is not a valid {}", s, target));
+    match target {
+        ValueType::I8 | ValueType::I16 | ValueType::I32 | ValueType::I64
+        | ValueType::U8 | ValueType::U16 | ValueType::U32 | ValueType::U64 => {
+            let n: i128 = s.trim().parse().map_err(|_| invalid())?;
+            from_integer(n, target)
+        },
+        ValueType::F32 => s.trim().parse().map(Value::F32).map_err(|_| invalid()),
+        ValueType::F64 => s.trim().parse().map(Value::F64).map_err(|_| invalid()),
+        ValueType::Bool => s.trim().parse().map(Value::Bool).map_err(|_| invalid()),
+        ValueType::Char => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Value::Char(c)),
+                _ => Err(invalid()),
+            }
+        },
+        target => Err(ConvError::Invalid(format!("cannot convert a string to {}", target))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions_are_checked() {
+        let s = |text: &str| Value::String(String::from(text));
+        assert_eq!(Ok(Value::I32(-7)), Value::F64(-7.9).convert(ValueType::I32));
+        assert_eq!(Ok(Value::Char('A')), Value::I64(65).convert(ValueType::Char));
+        assert_eq!(Ok(Value::I32(955)), Value::Char('λ').convert(ValueType::I32));
+        assert_eq!(Ok(Value::F32(1.0)), Value::Bool(true).convert(ValueType::F32));
+        assert_eq!(Ok(Value::I64(-42)), s(" -42 ").convert(ValueType::I64));
+        assert_eq!(Ok(s("2.5")), Value::F32(2.5).convert(ValueType::String));
+        assert_eq!(Ok(Value::Bool(false)), s("false").convert(ValueType::Bool));
+
+        assert_eq!(Err(ConvError::Overflow), Value::I64(1 << 40).convert(ValueType::I32));
+        assert_eq!(Err(ConvError::Overflow), Value::F64(f64::NAN).convert(ValueType::I64));
+        assert_eq!(Err(ConvError::Overflow), Value::F64(1e300).convert(ValueType::F32));
+        assert_eq!(Err(ConvError::Overflow), s("3000000000").convert(ValueType::I32));
+        assert!(matches!(Value::I32(0xD800).convert(ValueType::Char), Err(ConvError::Invalid(_))));
+        assert!(matches!(s("1.5").convert(ValueType::I32), Err(ConvError::Invalid(_))));
+        assert!(matches!(Value::Array(vec![]).convert(ValueType::I32), Err(ConvError::Invalid(_))));
+    }
+
+    #[test]
+    fn sized_integers_and_bytes_convert() {
+        assert_eq!(Ok(Value::U8(255)), Value::I64(255).convert(ValueType::U8));
+        assert_eq!(Err(ConvError::Overflow), Value::I8(-1).convert(ValueType::U64));
+        assert_eq!(Ok(Value::I16(-300)), Value::F32(-300.7).convert(ValueType::I16));
+        assert_eq!(Ok(Value::U64(u64::MAX)), Value::String(u64::MAX.to_string()).convert(ValueType::U64));
+        assert_eq!(Ok(Value::Bytes(vec![b'h', b'i'])), Value::String(String::from("hi")).convert(ValueType::Bytes));
+        assert_eq!(Ok(Value::String(String::from("hi"))), Value::Bytes(vec![b'h', b'i']).convert(ValueType::String));
+        assert!(matches!(Value::Bytes(vec![0xFF]).convert(ValueType::String), Err(ConvError::Invalid(_))));
+        assert_eq!(Ok(Value::Bytes(vec![1, 200])), Value::Array(vec![Value::I32(1), Value::U8(200)]).convert(ValueType::Bytes));
+        assert_eq!(Err(ConvError::Overflow), Value::Array(vec![Value::I32(256)]).convert(ValueType::Bytes));
+    }
+
+    #[test]
+    fn type_names_round_trip() {
+        for (t, name) in TYPES {
+            assert_eq!(Some(t), ValueType::from_name(name));
+            assert_eq!(Some(t), ValueType::from_index(t.index()));
+        }
+    }
+}
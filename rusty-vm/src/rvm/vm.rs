@@ -1,30 +1,91 @@
 //! Virtual Machine
 
-use serde_derive::{
-    Serialize
-};
 use std::{
-    collections::{
-        HashMap
-    }, rc::Rc,
+    cmp::Ordering,
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    rc::Rc,
 };
 
+use super::error::{Fault, HaltReason, VmError};
+use super::heap::{Heap, HeapError};
+use super::types::{ConvError, ValueType};
+use super::arith::{self, ArithError, BinaryOp, Outcome, ShiftOp, UnaryOp};
+use super::lowered::{Op, Opcode, Program};
+use super::port::{Port, PortTable};
+
 
 /// Values that the system is able to process
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
+    I8(i8),
+    I16(i16),
     I32(i32),
     I64(i64),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
     F32(f32),
     F64(f64),
     Char(char),
     String(String),
+    Bytes(Vec<u8>),
     Bool(bool),
     Symbol(Rc<Value>),
     Address(Option<usize>),
+    Array(Vec<Value>),
+    Tuple(Vec<Value>),
+    Map(Vec<(Value, Value)>),   // key/value pairs in insertion order
 }
 
-#[derive(Debug, Clone)]
+impl Value {
+    /// Orders two values for the compare instructions.
+    ///
+    /// Values of the same type compare naturally. Mixed integer types are
+    /// compared exactly and any other mix of numeric types is compared as f64.
+    /// `Ok(None)` means the operands are unordered, which only happens when
+    /// a NaN is involved. Any other combination is incomparable and is an error.
+    pub fn compare(&self, other: &Value) -> Result<Option<Ordering>, String> {
+        match (self, other) {
+            (Value::Char(l), Value::Char(r)) => Ok(Some(l.cmp(r))),
+            (Value::String(l), Value::String(r)) => Ok(Some(l.cmp(r))),
+            (Value::Bytes(l), Value::Bytes(r)) => Ok(Some(l.cmp(r))),
+            (Value::Bool(l), Value::Bool(r)) => Ok(Some(l.cmp(r))),
+            (Value::Symbol(l), Value::Symbol(r)) => l.compare(r),
+            (l, r) => match (l.as_integer(), r.as_integer(), l.as_f64(), r.as_f64()) {
+                (Some(li), Some(ri), _, _) => Ok(Some(li.cmp(&ri))),
+                (_, _, Some(lf), Some(rf)) => Ok(lf.partial_cmp(&rf)),
+                _ => Err(format!("{:?} and {:?} are not comparable", l, r))
+            }
+        }
+    }
+
+    /// The value of any integer type, widened so that it is exact
+    pub fn as_integer(&self) -> Option<i128> {
+        match *self {
+            Value::I8(n) => Some(n as i128),
+            Value::I16(n) => Some(n as i128),
+            Value::I32(n) => Some(n as i128),
+            Value::I64(n) => Some(n as i128),
+            Value::U8(n) => Some(n as i128),
+            Value::U16(n) => Some(n as i128),
+            Value::U32(n) => Some(n as i128),
+            Value::U64(n) => Some(n as i128),
+            _ => None
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::F32(n) => Some(*n as f64),
+            Value::F64(n) => Some(*n),
+            value => value.as_integer().map(|n| n as f64)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
     pub from: usize,
     pub to: usize,
@@ -33,41 +94,111 @@ pub struct Message {
 
 impl Message {
     pub fn get_message(&self) -> String {
-        match &self.value {
-            Value::Address(Some(addr)) => addr.to_string().clone(),
-            Value::Bool(b) => b.to_string().clone(),
-            Value::Char(c) => c.to_string().clone(),
-            Value::F32(f) => f.to_string().clone(),
-            Value::F64(f) => f.to_string().clone(),
-            Value::I32(i) => i.to_string().clone(),
-            Value::I64(i) => i.to_string().clone(),
-            Value::String(s) => s.clone(),
-            _ => String::from("unknown value")
+        self.value.to_string()
+    }
+}
 
+/// Formats values as `Print` shows them: strings and chars without quotes,
+/// symbols with a leading `#` and the null address as `null`. Strings and
+/// chars inside compound values are quoted. Bytes are written like a byte
+/// string literal, `b"..."`.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::I8(i) => write!(f, "{}", i),
+            Value::I16(i) => write!(f, "{}", i),
+            Value::I32(i) => write!(f, "{}", i),
+            Value::I64(i) => write!(f, "{}", i),
+            Value::U8(i) => write!(f, "{}", i),
+            Value::U16(i) => write!(f, "{}", i),
+            Value::U32(i) => write!(f, "{}", i),
+            Value::U64(i) => write!(f, "{}", i),
+            Value::F32(n) => write!(f, "{}", n),
+            Value::F64(n) => write!(f, "{}", n),
+            Value::Char(c) => write!(f, "{}", c),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Bytes(bytes) => {
+                write!(f, "b\"")?;
+                for byte in bytes {
+                    match byte {
+                        b'"' => write!(f, "\\\"")?,
+                        b'\\' => write!(f, "\\\\")?,
+                        b'\n' => write!(f, "\\n")?,
+                        b'\r' => write!(f, "\\r")?,
+                        b'\t' => write!(f, "\\t")?,
+                        b' '..=b'~' => write!(f, "{}", *byte as char)?,
+                        _ => write!(f, "\\x{:02x}", byte)?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Symbol(inner) => write!(f, "#{}", inner),
+            Value::Address(Some(addr)) => write!(f, "{}", addr),
+            Value::Address(None) => write!(f, "null"),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                write_items(f, items)?;
+                write!(f, "]")
+            },
+            Value::Tuple(items) => {
+                write!(f, "(")?;
+                write_items(f, items)?;
+                write!(f, ")")
+            },
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_item(f, key)?;
+                    write!(f, ": ")?;
+                    write_item(f, value)?;
+                }
+                write!(f, "}}")
+            },
         }
     }
 }
 
-pub static mut message_handler: Option<MessageHandler> = None;
-
-pub struct MessageHandler {
-    pub sender: Box<dyn Fn(usize, Message)>,
-    pub receiver: Box<dyn Fn(usize) -> Option<Message>>,     
+/// Applies a binary operator to two cells, which must both hold values
+fn cell_binary(op: BinaryOp, mode: ArithmeticMode, left: &MemoryCell, right: &MemoryCell) -> Result<Outcome, ArithError> {
+    match (left, right) {
+        (MemoryCell::Value(l), MemoryCell::Value(r)) => arith::binary(op, mode, l, r),
+        _ => Err(ArithError::Mismatch)
+    }
 }
 
-impl MessageHandler {
-    pub fn send(&mut self, port: usize, message: Message) {
-        (*self.sender)(port, message);
+/// True for float values that are NaN or infinite
+fn is_non_finite(value: &Value) -> bool {
+    match value {
+        Value::F32(x) => !x.is_finite(),
+        Value::F64(x) => !x.is_finite(),
+        _ => false,
     }
+}
 
-    pub fn receive(&mut self, port: usize) -> Option<Message> {
-        (*self.receiver)(port)
+fn write_items(f: &mut fmt::Formatter<'_>, items: &[Value]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write_item(f, item)?;
     }
+    Ok(())
 }
 
+fn write_item(f: &mut fmt::Formatter<'_>, item: &Value) -> fmt::Result {
+    match item {
+        Value::String(s) => write!(f, "{:?}", s),
+        Value::Char(c) => write!(f, "{:?}", c),
+        item => write!(f, "{}", item),
+    }
+}
 
 /// Instructions that the virtual machne will execute
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Nop,                        
     Push(Value),           
@@ -76,20 +207,361 @@ pub enum Instruction {
     Sub,                        
     Mul,                        
     Div,                        
+    AddWrap,                    // integer add that wraps on overflow
+    SubWrap,
+    MulWrap,
+    DivWrap,
+    AddSat,                     // integer add that saturates on overflow
+    SubSat,
+    MulSat,
+    DivSat,
+    AddR(usize, usize, usize),  // dst = a + b on registers
+    SubR(usize, usize, usize),  // dst = a - b on registers
+    MulR(usize, usize, usize),  // dst = a * b on registers
+    DivR(usize, usize, usize),  // dst = a / b on registers
+    Ld(usize),                  // push a register onto the stack
+    St(usize),                  // pop the stack into a register
+    Mov(usize, usize),          // copy register src into dst
+    LdImm(usize, Value),        // load a value into a register
     Jmp(Value),
+    Jz(Value),                  // jump if zero
+    Jnz(Value),                 // jump if not zero
+    Jneg(Value),                // jump if negative
+    Jnneg(Value),               // jump if not negative
+    Jpos(Value),                // jump if positive
+    Jnpos(Value),               // jump if not positive
+    Jeq(Value),                 // jump if equal
+    Jne(Value),                 // jump if not equal
+    Jlt(Value),                 // jump if less than
+    Jnlt(Value),                // jump if not less than
+    Jgt(Value),                 // jump if greater than
+    Jngt(Value),                // jump if not greater than
+    Call(Value, usize),         // call a subroutine taking n arguments from the stack
+    Ret,                        // return from a subroutine
+    LdLocal(usize),             // push local n of the current frame
+    StLocal(usize),             // pop into local n of the current frame
+    Alloc(usize),               // allocate n heap cells and push the address of the first
+    Free,                       // pop an address and free its heap block
+    Load,                       // pop an address and push the heap cell it points at
+    Store,                      // pop a value and an address and write the value to the heap
+    Gc,                         // run the garbage collector
+    NewArray(usize),            // pop n values into an array, the first pushed becoming element 0
+    NewTuple(usize),            // pop n values into a tuple
+    NewMap(usize),              // pop n key/value pairs into a map
+    Index,                      // pop an index and an array, tuple or bytes, push the element
+    SetIndex,                   // pop a value, an index and an array or bytes, push the update
+    Len,                        // pop an array, tuple, map or bytes and push its length
+    Append,                     // pop a value and an array or bytes, push it with the value added
+    MapGet,                     // pop a key and a map, push the value stored for the key
+    MapSet,                     // pop a value, a key and a map, push the updated map
+    Concat,                     // pop two strings or chars, or two bytes, and push them joined
+    Substr,                     // pop a length, a start and a string, push the chars in that range
+    StrLen,                     // pop a string and push its length in chars
+    CharAt,                     // pop an index and a string, push the char at that index
+    StrCmp,                     // pop two strings, push -1, 0 or 1 and set the compare flags
+    ToUpper,
+    ToLower,
+    Split,                      // pop a separator and a string, push an array of the parts
+    Find,                       // pop a needle and a string, push the char index of the needle or -1
+    Format(usize),              // pop n values and a format string, push it with each {} replaced
+    Conv(ValueType),            // pop a value and push it converted to the type
+    TypeOf,                     // pop a value and push the symbol naming its type, such as #"i32"
+    And,                        // bitwise and of two integers
+    Or,
+    Xor,
+    Not,                        // bitwise complement of an integer
+    Shl,                        // pop a bit count and an integer, push the integer shifted left
+    Shr,                        // logical shift right, filling with zeros
+    Sar,                        // shift right, arithmetic for signed types, logical for unsigned ones
+    Rotl,                       // pop a bit count and an integer, push the integer rotated left
+    Rotr,
+    LAnd,                       // logical and of two bools
+    LOr,
+    LNot,
+    Mod,                        // euclidean remainder, never negative
+    Rem,                        // remainder with the sign of the dividend
+    Neg,
+    Abs,
+    Min,
+    Max,
+    Pow,                        // pop an exponent and a base, push the power
+    Sqrt,                       // float functions, leaving the non_finite flag set on NaN or infinity
+    Sin,
+    Cos,
+    Tan,
+    Exp,
+    Ln,
+    Floor,                      // round a float down, leaving integers unchanged
+    Ceil,
+    Round,                      // round a float half away from zero
+    IsNaN,                      // pop a number and push whether it is NaN
+    Dup,                        // a -- a a
+    Dup2,                       // a b -- a b a b
+    Swap,                       // a b -- b a
+    Over,                       // a b -- a b a
+    Rot,                        // a b c -- b c a
+    Pick(usize),                // push a copy of the nth value below the top, Pick(0) being Dup
+    Roll(usize),                // move the nth value below the top to the top, Roll(1) being Swap
+    Depth,                      // push the number of values on the stack
+    Clear,                      // empty the stack
+    Cmp,                        // compare and pop the top two values
+    CmpKeep,                    // compare the top two values, leaving them on the stack
     Out(usize, Message),            
+    OutTop(usize),              // pop a value and send it on a port
+    Print,                      // pop a value and send it as text on port 0
+    In(usize),                  // push the value of the next message on a port
+    TryIn(usize),               // like In, but sets the zero flag instead of waiting when nothing arrived
     Halt,                       
     Dump,                       
 }
 
-#[derive(Debug, Clone)]
+/// Generates `Instruction::with_target` from the instruction table
+macro_rules! branches {
+    (|$vm:ident| $( $name:ident = $opcode:literal $mnemonic:literal $( ( $( $arg:ident : $kind:ident ),* ) )? => $body:expr, )*) => {
+        /// Returns a copy of a branch instruction pointing at `address`, or
+        /// `None` if the instruction does not take a jump target
+        pub fn with_target(&self, address: usize) -> Option<Instruction> {
+            match self {
+                $( Instruction::$name $( ( $( $arg ),* ) )? => {
+                    if !(false $( $( || retarget!($kind) )* )?) {
+                        return None;
+                    }
+                    Some(Instruction::$name $( ( $( retarget!($kind, $arg, address) ),* ) )?)
+                } )*
+            }
+        }
+    };
+}
+
+/// Replaces a jump target operand, keeping any other
+macro_rules! retarget {
+    (target) => { true };
+    ($kind:ident) => { false };
+    (target, $arg:ident, $address:ident) => {{ let _ = $arg; Value::Address(Some($address)) }};
+    ($kind:ident, $arg:ident, $address:ident) => { $arg.clone() };
+}
+
+impl Instruction {
+    instruction_table!(branches);
+
+    /// True for instructions that take a jump target
+    pub fn is_branch(&self) -> bool {
+        self.with_target(0).is_some()
+    }
+}
+
+/// The instruction set as a single table, handed to the macro `$callback`,
+/// from which `RustyVM::execute`, `RustyVM::dispatch`, lowering, the image
+/// encoding and the assembler and disassembler are all generated. An entry
+/// names an `Instruction` variant, its image opcode and assembler mnemonic,
+/// gives each of its fields a name and an operand kind, and says how `vm`
+/// executes it. Opcodes are part of the image format, so a new instruction
+/// takes the next free one.
+///
+/// Operand kinds are `register`, `count` for counts and ports, and `argc`
+/// for a count the assembler lets a program leave out; `constant` and
+/// `message` for payloads kept in the pools of a lowered program; `target`
+/// for a jump target and `value_type` for a `ValueType`. Payloads are
+/// borrowed, so entries clone them only when the VM keeps them.
+#[macro_export]
+#[doc(hidden)]
+macro_rules! instruction_table {
+    ($callback:ident) => {
+        $callback! { |vm|
+            Nop = 0 "nop" => vm.ex_nop(),
+            Push = 1 "push" (value: constant) => vm.ex_push(value.clone()),
+            Pop = 2 "pop" => vm.ex_pop(),
+            Add = 3 "add" => vm.ex_arith(BinaryOp::Add, vm.arith_mode),
+            Sub = 4 "sub" => vm.ex_arith(BinaryOp::Sub, vm.arith_mode),
+            Mul = 5 "mul" => vm.ex_arith(BinaryOp::Mul, vm.arith_mode),
+            Div = 6 "div" => vm.ex_arith(BinaryOp::Div, vm.arith_mode),
+            AddWrap = 7 "add_wrap" => vm.ex_arith(BinaryOp::Add, ArithmeticMode::Wrapping),
+            SubWrap = 8 "sub_wrap" => vm.ex_arith(BinaryOp::Sub, ArithmeticMode::Wrapping),
+            MulWrap = 9 "mul_wrap" => vm.ex_arith(BinaryOp::Mul, ArithmeticMode::Wrapping),
+            DivWrap = 10 "div_wrap" => vm.ex_arith(BinaryOp::Div, ArithmeticMode::Wrapping),
+            AddSat = 11 "add_sat" => vm.ex_arith(BinaryOp::Add, ArithmeticMode::Saturating),
+            SubSat = 12 "sub_sat" => vm.ex_arith(BinaryOp::Sub, ArithmeticMode::Saturating),
+            MulSat = 13 "mul_sat" => vm.ex_arith(BinaryOp::Mul, ArithmeticMode::Saturating),
+            DivSat = 14 "div_sat" => vm.ex_arith(BinaryOp::Div, ArithmeticMode::Saturating),
+            AddR = 15 "add_r" (dst: register, a: register, b: register) => vm.ex_arith_reg(BinaryOp::Add, dst, a, b),
+            SubR = 16 "sub_r" (dst: register, a: register, b: register) => vm.ex_arith_reg(BinaryOp::Sub, dst, a, b),
+            MulR = 17 "mul_r" (dst: register, a: register, b: register) => vm.ex_arith_reg(BinaryOp::Mul, dst, a, b),
+            DivR = 18 "div_r" (dst: register, a: register, b: register) => vm.ex_arith_reg(BinaryOp::Div, dst, a, b),
+            Ld = 19 "ld" (reg: register) => vm.ex_ld(reg),
+            St = 20 "st" (reg: register) => vm.ex_st(reg),
+            Mov = 21 "mov" (dst: register, src: register) => vm.ex_mov(dst, src),
+            LdImm = 22 "ld_imm" (reg: register, value: constant) => vm.ex_ld_imm(reg, value.clone()),
+            Jmp = 23 "jmp" (address: target) => vm.ex_jump(address),
+            Jz = 24 "jz" (address: target) => vm.ex_branch(vm.flags.zero, address),
+            Jnz = 25 "jnz" (address: target) => vm.ex_branch(!vm.flags.zero, address),
+            Jneg = 26 "jneg" (address: target) => vm.ex_branch(vm.flags.neg, address),
+            Jnneg = 27 "jnneg" (address: target) => vm.ex_branch(!vm.flags.neg, address),
+            Jpos = 28 "jpos" (address: target) => vm.ex_branch(vm.flags.pos, address),
+            Jnpos = 29 "jnpos" (address: target) => vm.ex_branch(!vm.flags.pos, address),
+            Jeq = 30 "jeq" (address: target) => vm.ex_branch(vm.flags.equal, address),
+            Jne = 31 "jne" (address: target) => vm.ex_branch(!vm.flags.equal, address),
+            Jlt = 32 "jlt" (address: target) => vm.ex_branch(vm.flags.less_than, address),
+            Jnlt = 33 "jnlt" (address: target) => vm.ex_branch(!vm.flags.less_than, address),
+            Jgt = 34 "jgt" (address: target) => vm.ex_branch(vm.flags.great_than, address),
+            Jngt = 35 "jngt" (address: target) => vm.ex_branch(!vm.flags.great_than, address),
+            Call = 36 "call" (address: target, argc: argc) => vm.ex_call(address, argc),
+            Ret = 37 "ret" => vm.ex_ret(),
+            LdLocal = 38 "ld_local" (n: count) => vm.ex_ld_local(n),
+            StLocal = 39 "st_local" (n: count) => vm.ex_st_local(n),
+            Cmp = 40 "cmp" => vm.ex_cmp(false),
+            CmpKeep = 41 "cmp_keep" => vm.ex_cmp(true),
+            Out = 42 "out" (port: count, message: message) => vm.ex_out(port, message.clone()),
+            Halt = 43 "halt" => vm.ex_halt(),
+            Dump = 44 "dump" => vm.ex_dump(),
+            In = 45 "in" (port: count) => vm.ex_in(port, vm.blocking_input),
+            TryIn = 46 "try_in" (port: count) => vm.ex_try_in(port),
+            OutTop = 47 "out_top" (port: count) => vm.ex_out_top(port, false),
+            Print = 48 "print" => vm.ex_out_top(0, true),
+            Alloc = 49 "alloc" (n: count) => vm.ex_alloc(n),
+            Free = 50 "free" => vm.ex_free(),
+            Load = 51 "load" => vm.ex_load(),
+            Store = 52 "store" => vm.ex_store(),
+            Gc = 53 "gc" => vm.ex_gc(),
+            NewArray = 54 "new_array" (n: count) => vm.ex_new_collection(n, "NewArray", Value::Array),
+            NewTuple = 55 "new_tuple" (n: count) => vm.ex_new_collection(n, "NewTuple", Value::Tuple),
+            NewMap = 56 "new_map" (n: count) => vm.ex_new_map(n),
+            Index = 57 "index" => vm.ex_index(),
+            SetIndex = 58 "set_index" => vm.ex_set_index(),
+            Len = 59 "len" => vm.ex_len(),
+            Append = 60 "append" => vm.ex_append(),
+            MapGet = 61 "map_get" => vm.ex_map_get(),
+            MapSet = 62 "map_set" => vm.ex_map_set(),
+            Concat = 63 "concat" => vm.ex_concat(),
+            Substr = 64 "substr" => vm.ex_substr(),
+            StrLen = 65 "str_len" => vm.ex_str_len(),
+            CharAt = 66 "char_at" => vm.ex_char_at(),
+            StrCmp = 67 "str_cmp" => vm.ex_str_cmp(),
+            ToUpper = 68 "to_upper" => vm.ex_map_str("ToUpper", |s| s.to_uppercase()),
+            ToLower = 69 "to_lower" => vm.ex_map_str("ToLower", |s| s.to_lowercase()),
+            Split = 70 "split" => vm.ex_split(),
+            Find = 71 "find" => vm.ex_find(),
+            Format = 72 "format" (n: count) => vm.ex_format(n),
+            Conv = 73 "conv" (ty: value_type) => vm.ex_conv(ty),
+            TypeOf = 74 "type_of" => vm.ex_type_of(),
+            And = 75 "and" => vm.ex_arith(BinaryOp::And, vm.arith_mode),
+            Or = 76 "or" => vm.ex_arith(BinaryOp::Or, vm.arith_mode),
+            Xor = 77 "xor" => vm.ex_arith(BinaryOp::Xor, vm.arith_mode),
+            Not = 78 "not" => vm.ex_unary(UnaryOp::Not),
+            Shl = 79 "shl" => vm.ex_shift(ShiftOp::Shl),
+            Shr = 80 "shr" => vm.ex_shift(ShiftOp::Shr),
+            Sar = 81 "sar" => vm.ex_shift(ShiftOp::Sar),
+            Rotl = 82 "rotl" => vm.ex_shift(ShiftOp::Rotl),
+            Rotr = 83 "rotr" => vm.ex_shift(ShiftOp::Rotr),
+            LAnd = 84 "land" => vm.ex_logical("LAnd", |l, r| l && r),
+            LOr = 85 "lor" => vm.ex_logical("LOr", |l, r| l || r),
+            LNot = 86 "lnot" => vm.ex_lnot(),
+            Dup = 87 "dup" => vm.ex_pick(0),
+            Dup2 = 88 "dup2" => vm.ex_dup2(),
+            Swap = 89 "swap" => vm.ex_roll(1),
+            Over = 90 "over" => vm.ex_pick(1),
+            Rot = 91 "rot" => vm.ex_roll(2),
+            Pick = 92 "pick" (n: count) => vm.ex_pick(n),
+            Roll = 93 "roll" (n: count) => vm.ex_roll(n),
+            Depth = 94 "depth" => vm.ex_depth(),
+            Clear = 95 "clear" => vm.ex_clear(),
+            Mod = 96 "mod" => vm.ex_arith(BinaryOp::Mod, vm.arith_mode),
+            Rem = 97 "rem" => vm.ex_arith(BinaryOp::Rem, vm.arith_mode),
+            Neg = 98 "neg" => vm.ex_unary(UnaryOp::Neg),
+            Abs = 99 "abs" => vm.ex_unary(UnaryOp::Abs),
+            Min = 100 "min" => vm.ex_arith(BinaryOp::Min, vm.arith_mode),
+            Max = 101 "max" => vm.ex_arith(BinaryOp::Max, vm.arith_mode),
+            Pow = 102 "pow" => vm.ex_arith(BinaryOp::Pow, vm.arith_mode),
+            Sqrt = 103 "sqrt" => vm.ex_float_fn("Sqrt", f64::sqrt, false),
+            Sin = 104 "sin" => vm.ex_float_fn("Sin", f64::sin, false),
+            Cos = 105 "cos" => vm.ex_float_fn("Cos", f64::cos, false),
+            Tan = 106 "tan" => vm.ex_float_fn("Tan", f64::tan, false),
+            Exp = 107 "exp" => vm.ex_float_fn("Exp", f64::exp, false),
+            Ln = 108 "ln" => vm.ex_float_fn("Ln", f64::ln, false),
+            Floor = 109 "floor" => vm.ex_float_fn("Floor", f64::floor, true),
+            Ceil = 110 "ceil" => vm.ex_float_fn("Ceil", f64::ceil, true),
+            Round = 111 "round" => vm.ex_float_fn("Round", f64::round, true),
+            IsNaN = 112 "is_nan" => vm.ex_is_nan(),
+        }
+    };
+}
+pub use instruction_table;
+
+/// Generates `execute`, which runs an `Instruction`, and `dispatch`, which
+/// runs a lowered `Op`, from the instruction table
+macro_rules! interpreters {
+    (|$vm:ident| $( $name:ident = $opcode:literal $mnemonic:literal $( ( $( $arg:ident : $kind:ident ),* ) )? => $body:expr, )*) => {
+        fn execute(&mut self, inst: &Instruction) -> Result<(), VmError> {
+            let $vm = self;
+            match inst {
+                $( Instruction::$name $( ( $( $arg ),* ) )? => {
+                    $( $( let $arg = operand!($kind, $vm, $arg); )* )?
+                    $body
+                } )*
+            }
+        }
+
+        /// Executes a lowered op, exactly as `execute` runs the instruction it came from
+        fn dispatch(&mut self, program: &Program, op: Op) -> Result<(), VmError> {
+            let $vm = self;
+            match op.opcode {
+                $( Opcode::$name => {
+                    $( lowered_operands!($vm, program, op, [a b c] $( $arg : $kind ),*); )?
+                    $body
+                } )*
+                Opcode::Unresolved => Err(VmError::UnresolvedLabel($vm.fault())),
+                Opcode::Invalid => Err(VmError::InvalidInstruction($vm.fault())),
+                Opcode::Fallback => $vm.execute(program.fallback_at(op.a)),
+            }
+        }
+    };
+}
+
+/// Reads an operand of an instruction, which `execute` matched by reference
+macro_rules! operand {
+    (register, $vm:ident, $arg:ident) => { *$arg };
+    (count, $vm:ident, $arg:ident) => { *$arg };
+    (argc, $vm:ident, $arg:ident) => { *$arg };
+    (constant, $vm:ident, $arg:ident) => { $arg };
+    (message, $vm:ident, $arg:ident) => { $arg };
+    (value_type, $vm:ident, $arg:ident) => { *$arg };
+    // branches whose target is not an address were never patched by the builder
+    (target, $vm:ident, $arg:ident) => {
+        match $arg {
+            Value::Address(Some(address)) => *address,
+            _ => return Err(VmError::UnresolvedLabel($vm.fault()))
+        }
+    };
+}
+
+/// Binds the operands of a lowered op, in field order from `a`, `b` and `c`
+macro_rules! lowered_operands {
+    ($vm:ident, $program:ident, $op:ident, [$slot:ident $( $slots:ident )*] $arg:ident : $kind:ident $( , $args:ident : $kinds:ident )*) => {
+        let $arg = lowered_operands!(@ $kind, $vm, $program, $op.$slot);
+        lowered_operands!($vm, $program, $op, [$( $slots )*] $( $args : $kinds ),*);
+    };
+    ($vm:ident, $program:ident, $op:ident, [$( $slots:ident )*]) => {};
+    (@ register, $vm:ident, $program:ident, $operand:expr) => { $operand as usize };
+    (@ count, $vm:ident, $program:ident, $operand:expr) => { $operand as usize };
+    (@ argc, $vm:ident, $program:ident, $operand:expr) => { $operand as usize };
+    (@ target, $vm:ident, $program:ident, $operand:expr) => { $operand as usize };
+    (@ constant, $vm:ident, $program:ident, $operand:expr) => { $program.constant_at($operand) };
+    (@ message, $vm:ident, $program:ident, $operand:expr) => { $program.message_at($operand) };
+    (@ value_type, $vm:ident, $program:ident, $operand:expr) => {
+        match ValueType::from_index($operand as usize) {
+            Some(target) => target,
+            None => return Err(VmError::InvalidInstruction($vm.fault()))
+        }
+    };
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum MetaData {
     Tag(String),
 
 }
 
 /// Describes what can be stored in a memory location
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MemoryCell {
     Instruction(Instruction),
     Value(Value),
@@ -97,14 +569,18 @@ pub enum MemoryCell {
     Empty
 }
 
+/// Condition flags set by arithmetic and compare instructions
 #[derive(Debug, Clone)]
-struct Flags {
+pub struct Flags {
     pub zero: bool,         // set by arithmentic operations
     pub neg: bool,          // set by arithmentic operations
     pub pos: bool,          // set by arithmentic operations
     pub equal: bool,        // set by compare
     pub less_than: bool,    // set by compare
-    pub great_than: bool    // set by compare
+    pub great_than: bool,   // set by compare
+    pub overflow: bool,     // set by integer arithmetic when the result did not fit
+    pub carry: bool,        // set by integer add/sub on unsigned carry or borrow
+    pub non_finite: bool    // set by float arithmetic when the result is NaN or infinite
 }
 
 impl Flags {
@@ -115,7 +591,10 @@ impl Flags {
             pos: false,
             equal: false,
             less_than: false,
-            great_than: false
+            great_than: false,
+            overflow: false,
+            carry: false,
+            non_finite: false
         }
     }
 
@@ -126,11 +605,41 @@ impl Flags {
         self.equal = false;
         self.less_than = false;
         self.great_than = false;
+        self.overflow = false;
+        self.carry = false;
+        self.non_finite = false;
     }
 
 }
 
+/// How integer arithmetic treats results that do not fit in the operand type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    #[default]
+    Checked,        // raise a VM exception
+    Wrapping,       // wrap around at the type boundary
+    Saturating,     // clamp to the type's minimum or maximum
+}
+
+/// Number of general purpose registers
+pub const REGISTER_COUNT: usize = 16;
+
+/// Default limit on nested calls before the VM raises an exception
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+/// An activation record on the call stack
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub return_address: usize,
+    pub frame_pointer: usize,   // stack index of the first argument / local
+    pub argc: usize,            // arguments passed, shown by debuggers; locals may go beyond them
+}
+
 /// The virtual machine
+///
+/// A VM is single threaded: devices are shared between clones through
+/// `Rc<RefCell<_>>` and symbols are `Rc`s, so it is neither `Send` nor `Sync`.
+/// Build it on the thread that runs it.
 #[derive(Debug, Clone)]
 pub struct RustyVM {
     pc: usize, // program counter
@@ -138,13 +647,28 @@ pub struct RustyVM {
     memory: Vec<MemoryCell>,
     stack: Vec<MemoryCell>,
     registers: Vec<MemoryCell>,
-    heap: Vec<Value>,
+    heap: Heap,
     flags: Flags,
+    call_stack: Vec<Frame>,
+    max_call_depth: usize,
+    arith_mode: ArithmeticMode,
+    symbols: BTreeMap<String, usize>,
+    id: usize,                                      // sender id of messages from OutTop and Print
+    ports: PortTable,
+    inbox: BTreeMap<usize, VecDeque<Message>>,     // input fed by the host, read before the device
+    blocking_input: bool,
+    waiting: Option<usize>,                         // port an In instruction is waiting on
     // special registers
     cur_instruction: Option<Instruction>
 
 }
 
+impl Default for RustyVM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RustyVM {
     pub fn new() -> Self {
 
@@ -155,10 +679,19 @@ impl RustyVM {
             registers: vec![],
             running: false,
             cur_instruction: None,
-            heap: vec![],
-            flags: Flags::new()
+            heap: Heap::new(),
+            flags: Flags::new(),
+            call_stack: vec![],
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            arith_mode: ArithmeticMode::default(),
+            symbols: BTreeMap::new(),
+            id: 0,
+            ports: PortTable::new(),
+            inbox: BTreeMap::new(),
+            blocking_input: false,
+            waiting: None
         };
-        for i in 0..16 {
+        for _ in 0..REGISTER_COUNT {
             vm.registers.push(MemoryCell::Empty)
         }
         vm
@@ -168,16 +701,116 @@ impl RustyVM {
         self.stack.clone()
     }
 
-    fn handle_exception(&mut self, msg: &str) {
-        println!("Exception({}): {}\nCurrent Instruction\n{:#?}", self.pc, msg, self.cur_instruction);
-        self.ex_dump();
-        self.running = false;
+    pub fn stack(&self) -> &[MemoryCell] {
+        &self.stack
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// False once the VM halted or raised an exception, until `reset`
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn registers(&self) -> &[MemoryCell] {
+        &self.registers
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    /// Active frames, innermost last
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arith_mode = mode;
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Sets the id used as `from` in the messages this VM sends
+    pub fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+
+    /// Collects garbage automatically once `cells` heap cells have been
+    /// allocated since the last collection. `None` leaves freeing to the program.
+    pub fn set_gc_threshold(&mut self, cells: Option<usize>) {
+        self.heap.set_gc_threshold(cells);
+    }
+
+    /// Limits the heap to `cells` cells, beyond which `Alloc` raises `OutOfMemory`
+    pub fn set_max_heap_cells(&mut self, cells: usize) {
+        self.heap.set_max_cells(cells);
+    }
+
+    /// Connects `device` to `port`, replacing any device already attached there
+    pub fn attach(&mut self, port: usize, device: impl Port + 'static) {
+        self.ports.attach(port, device);
+    }
+
+    /// When enabled, an `In` with no input available suspends the VM with
+    /// `HaltReason::WaitingForInput` instead of raising an exception
+    pub fn set_blocking_input(&mut self, blocking: bool) {
+        self.blocking_input = blocking;
+    }
+
+    /// Queues a message for the program to read from `port`. Input fed by
+    /// the host is read before anything the port's device provides.
+    pub fn feed(&mut self, port: usize, message: Message) {
+        self.inbox.entry(port).or_default().push_back(message);
+    }
+
+    /// Disconnects the device on `port`, returning false if there was none
+    pub fn detach(&mut self, port: usize) -> bool {
+        self.ports.detach(port)
+    }
+
+    /// Captures the current pc and instruction for an error. `run_lowered`
+    /// does not fetch instructions, so then the instruction is read from memory.
+    fn fault(&self) -> Fault {
+        let instruction = match (&self.cur_instruction, self.memory.get(self.pc)) {
+            (Some(inst), _) | (None, Some(MemoryCell::Instruction(inst))) => Some(inst.clone()),
+            _ => None
+        };
+        Fault {
+            pc: self.pc,
+            instruction
+        }
     }
 
     pub fn push(&mut self, mem: MemoryCell) {
         self.memory.push(mem);
     }
 
+    pub fn memory(&self) -> &[MemoryCell] {
+        &self.memory
+    }
+
+    /// Names for program addresses, as defined by builder labels
+    pub fn symbols(&self) -> &BTreeMap<String, usize> {
+        &self.symbols
+    }
+
+    pub fn add_symbol(&mut self, name: &str, address: usize) {
+        self.symbols.insert(name.to_string(), address);
+    }
+
     pub fn get_instruction(&mut self, address: usize) -> MemoryCell {
         self.memory[address].clone()
     }
@@ -189,274 +822,970 @@ impl RustyVM {
     pub fn reset(&mut self) {
         self.pc = 0;
         self.running = true;
+        self.flags.reset();
+        self.call_stack.clear();
     }
 
-    fn fetch(&mut self) {
-        let cell = &self.memory[self.pc];
-
-        match cell {
-            MemoryCell::Instruction(i) => self.cur_instruction = Some(i.clone()),
-            _ => self.handle_exception("Invalid Instruction in MemoryCell")
-        };
+    fn fetch(&mut self) -> Result<(), VmError> {
+        match self.memory.get(self.pc) {
+            Some(MemoryCell::Instruction(i)) => {
+                self.cur_instruction = Some(i.clone());
+                Ok(())
+            },
+            Some(_) => {
+                self.cur_instruction = None;
+                Err(VmError::InvalidInstruction(self.fault()))
+            },
+            None => {
+                self.cur_instruction = None;
+                Err(VmError::PcOutOfBounds(self.fault()))
+            }
+        }
     }
 
-    fn decode(&mut self) {
+    fn decode(&mut self) -> Result<(), VmError> {
         match self.cur_instruction.clone() {
-            Some(inst) => self.execute(inst),
-            None => self.handle_exception("Invalid Instruction in MemoryCell")
-        };
+            Some(inst) => self.execute(&inst),
+            None => Err(VmError::InvalidInstruction(self.fault()))
+        }
+    }
+
+    instruction_table!(interpreters);
+
+    fn ex_nop(&mut self) -> Result<(), VmError> {
+        self.pc += 1;
+        Ok(())
     }
 
-    fn execute(&mut self, inst: Instruction) {
-        match inst {
-            Instruction::Nop => self.pc += 1,
+    fn pop(&mut self) -> Result<MemoryCell, VmError> {
+        match self.stack.pop() {
+            Some(cell) => Ok(cell),
+            None => Err(VmError::StackUnderflow(self.fault()))
+        }
+    }
 
-            Instruction::Add => self.ex_add(),
-            Instruction::Sub => self.ex_sub(),
-            Instruction::Div => self.ex_div(),
-            Instruction::Mul => self.ex_mul(),
+    fn ex_arith(&mut self, op: BinaryOp, mode: ArithmeticMode) -> Result<(), VmError> {
+        if self.stack.len() < 2 {
+            return Err(VmError::StackUnderflow(self.fault()));
+        }
+        let right = self.pop()?;
+        let left = self.pop()?;
+        let res = self.binary(op, mode, &left, &right)?;
+        self.stack.push(MemoryCell::Value(res));
+        self.pc += 1;
+        Ok(())
+    }
 
-            Instruction::Jmp(Value::Address(Some(addr))) => self.ex_jump(addr),
+    fn ex_arith_reg(&mut self, op: BinaryOp, dst: usize, a: usize, b: usize) -> Result<(), VmError> {
+        self.check_register(dst)?;
+        // operands are borrowed rather than cloned, as this is a hot path
+        let (left, right) = (self.register(a)?, self.register(b)?);
+        let outcome = cell_binary(op, self.arith_mode, left, right);
+        if let Err(ArithError::Mismatch) = outcome {
+            return Err(self.mismatch(op, left, right));
+        }
+        let res = self.arith_result(op, outcome)?;
+        self.registers[dst] = MemoryCell::Value(res);
+        self.pc += 1;
+        Ok(())
+    }
 
-            Instruction::Push(x) => self.ex_push(x),
-            Instruction::Pop => self.ex_pop(),
-            Instruction::Dump => self.ex_dump(),
-            Instruction::Halt => self.ex_halt(),
-            Instruction::Out(port, message) => self.ex_out(port, message),
-            _ => {}
-        };
+    /// Applies a binary operator through the shared arithmetic engine and
+    /// sets the flags from its outcome
+    fn binary(&mut self, op: BinaryOp, mode: ArithmeticMode, left: &MemoryCell, right: &MemoryCell) -> Result<Value, VmError> {
+        match cell_binary(op, mode, left, right) {
+            Err(ArithError::Mismatch) => Err(self.mismatch(op, left, right)),
+            outcome => self.arith_result(op, outcome)
+        }
     }
 
-    fn ex_add(&mut self) {
-        let right = self.stack.pop();
-        let left = self.stack.pop();
-        match (left, right) {
-            (Some(MemoryCell::Value(Value::I32(l))), 
-                Some(MemoryCell::Value(Value::I32(r)))) =>
-                {
-                    let res = l + r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I32(res)) )
-                },
-            (Some(MemoryCell::Value(Value::I64(l))), 
-                Some(MemoryCell::Value(Value::I64(r)))) => {
-                    let res = l + r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I64(res)) )
-                },
+    fn mismatch(&self, op: BinaryOp, left: &MemoryCell, right: &MemoryCell) -> VmError {
+        VmError::TypeMismatch(self.fault(),
+            format!("{}: operands must be {} of the same type or of types that widen losslessly: {:?} and {:?}", op.name(), op.operands(), left, right))
+    }
 
-            (Some(MemoryCell::Value(Value::F32(l))), 
-                Some(MemoryCell::Value(Value::F32(r)))) => {
-                    let res = l + r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F32(res)) )
-            },
-            (Some(MemoryCell::Value(Value::F64(l))), 
-                Some(MemoryCell::Value(Value::F64(r)))) => {
-                    let res = l + r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F64(res)) )
+    fn arith_result(&mut self, op: BinaryOp, outcome: Result<Outcome, ArithError>) -> Result<Value, VmError> {
+        match outcome {
+            Ok(outcome) => Ok(self.apply_outcome(outcome)),
+            Err(err) => Err(self.arith_error(op.name(), err))
+        }
+    }
+
+    fn apply_outcome(&mut self, outcome: Outcome) -> Value {
+        self.flags.overflow = outcome.overflow;
+        self.flags.carry = outcome.carry;
+        self.set_numeric_flags(&outcome.value);
+        outcome.value
+    }
+
+    fn arith_error(&mut self, op: &str, err: ArithError) -> VmError {
+        match err {
+            ArithError::Overflow => {
+                self.flags.overflow = true;
+                VmError::Overflow(self.fault())
             },
+            ArithError::DivideByZero => VmError::DivideByZero(self.fault()),
+            ArithError::NegativeExponent => VmError::TypeMismatch(self.fault(), format!("{}: integer exponents must not be negative", op)),
+            ArithError::Mismatch => VmError::TypeMismatch(self.fault(), format!("{}: operands must be numbers", op))
+        }
+    }
 
-            (l,r) => {
-                self.handle_exception(format!("Add: left and right operands must be of the same type: {:#?} + {:#?}", l, r).as_str())
-            }
+    fn check_register(&self, reg: usize) -> Result<(), VmError> {
+        if reg < self.registers.len() {
+            Ok(())
+        } else {
+            Err(VmError::InvalidRegister(self.fault(), reg))
+        }
+    }
+
+    /// Reads a register that must hold a value
+    fn register(&self, reg: usize) -> Result<&MemoryCell, VmError> {
+        self.check_register(reg)?;
+        match &self.registers[reg] {
+            MemoryCell::Empty => Err(VmError::EmptyRegister(self.fault(), reg)),
+            cell => Ok(cell)
         }
+    }
+
+    fn ex_ld(&mut self, reg: usize) -> Result<(), VmError> {
+        let cell = self.register(reg)?.clone();
+        self.stack.push(cell);
         self.pc += 1;
+        Ok(())
     }
 
-    fn ex_sub(&mut self) {
-        let right = self.stack.pop();
-        let left = self.stack.pop();
-        match (left, right) {
-            (Some(MemoryCell::Value(Value::I32(l))), 
-                Some(MemoryCell::Value(Value::I32(r)))) =>
-                {
-                    let res = l - r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I32(res)) )
-                },
-            (Some(MemoryCell::Value(Value::I64(l))), 
-                Some(MemoryCell::Value(Value::I64(r)))) => {
-                    let res = l - r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I64(res)) )
-                },
+    fn ex_st(&mut self, reg: usize) -> Result<(), VmError> {
+        self.check_register(reg)?;
+        self.registers[reg] = self.pop()?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_mov(&mut self, dst: usize, src: usize) -> Result<(), VmError> {
+        self.check_register(dst)?;
+        self.check_register(src)?;
+        self.registers[dst] = self.registers[src].clone();
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_ld_imm(&mut self, reg: usize, value: Value) -> Result<(), VmError> {
+        self.check_register(reg)?;
+        self.registers[reg] = MemoryCell::Value(value);
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Sets the zero, neg, pos and non_finite flags from a numeric result
+    fn set_numeric_flags(&mut self, res: &Value) {
+        let (zero, pos, neg) = match *res {
+            Value::F32(x) => (x == 0.0, x > 0.0, x < 0.0),
+            Value::F64(x) => (x == 0.0, x > 0.0, x < 0.0),
+            ref value => match value.as_integer() {
+                Some(n) => (n == 0, n > 0, n < 0),
+                None => (false, false, false),
+            },
+        };
+        self.flags.zero = zero;
+        self.flags.pos = pos;
+        self.flags.neg = neg;
+        self.flags.non_finite = is_non_finite(res);
+    }
+
+    fn ex_unary(&mut self, op: UnaryOp) -> Result<(), VmError> {
+        let value = self.pop_value(op.name())?;
+        match arith::unary(op, self.arith_mode, &value) {
+            Ok(outcome) => {
+                let res = self.apply_outcome(outcome);
+                self.push_result(res)
+            },
+            Err(ArithError::Mismatch) => Err(self.wrong_type(op.name(), op.operand(), &value)),
+            Err(err) => Err(self.arith_error(op.name(), err))
+        }
+    }
+
+    /// Applies a float function, computing F32 values at double precision.
+    /// Rounding functions leave integers unchanged.
+    fn ex_float_fn(&mut self, op: &str, f: fn(f64) -> f64, rounding: bool) -> Result<(), VmError> {
+        let res = match self.pop_value(op)? {
+            Value::F32(x) => Value::F32(f(x as f64) as f32),
+            Value::F64(x) => Value::F64(f(x)),
+            value if rounding && value.as_integer().is_some() => value,
+            value => return Err(self.wrong_type(op, "a float", &value)),
+        };
+        self.flags.overflow = false;
+        self.flags.carry = false;
+        self.set_numeric_flags(&res);
+        self.push_result(res)
+    }
+
+    fn ex_is_nan(&mut self) -> Result<(), VmError> {
+        let nan = match self.pop_value("IsNaN")? {
+            Value::F32(x) => x.is_nan(),
+            Value::F64(x) => x.is_nan(),
+            value if value.as_integer().is_some() => false,
+            value => return Err(self.wrong_type("IsNaN", "a number", &value)),
+        };
+        self.set_result_flags(nan as i64);
+        self.push_result(Value::Bool(nan))
+    }
+
+    /// Sets the zero, neg and pos flags from an integer result, as arithmetic does
+    fn set_result_flags(&mut self, res: i64) {
+        self.flags.overflow = false;
+        self.flags.carry = false;
+        self.flags.non_finite = false;
+        self.flags.zero = res == 0;
+        self.flags.pos = res > 0;
+        self.flags.neg = res < 0;
+    }
 
-            (Some(MemoryCell::Value(Value::F32(l))), 
-                Some(MemoryCell::Value(Value::F32(r)))) => {
-                    let res = l - r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F32(res)) )
+    /// Pops a bit count and an integer
+    fn ex_shift(&mut self, op: ShiftOp) -> Result<(), VmError> {
+        let count = self.pop_value(op.name())?;
+        let count = match count.as_integer() {
+            Some(count) => count,
+            None => return Err(self.wrong_type(op.name(), "an integer bit count", &count)),
+        };
+        let value = self.pop_value(op.name())?;
+        match arith::shift(op, &value, count) {
+            Ok(res) => {
+                self.flags.overflow = false;
+                self.flags.carry = false;
+                self.set_numeric_flags(&res);
+                self.push_result(res)
             },
-            (Some(MemoryCell::Value(Value::F64(l))), 
-                Some(MemoryCell::Value(Value::F64(r)))) => {
-                    let res = l - r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F64(res)) )
+            Err(ArithError::Mismatch) => Err(self.wrong_type(op.name(), "an integer", &value)),
+            Err(err) => Err(self.arith_error(op.name(), err))
+        }
+    }
+
+    fn pop_bool(&mut self, op: &str) -> Result<bool, VmError> {
+        match self.pop_value(op)? {
+            Value::Bool(b) => Ok(b),
+            value => Err(self.wrong_type(op, "a bool", &value)),
+        }
+    }
+
+    fn ex_logical(&mut self, op: &str, logic: fn(bool, bool) -> bool) -> Result<(), VmError> {
+        if self.stack.len() < 2 {
+            return Err(VmError::StackUnderflow(self.fault()));
+        }
+        let right = self.pop_bool(op)?;
+        let left = self.pop_bool(op)?;
+        let res = logic(left, right);
+        self.set_result_flags(res as i64);
+        self.push_result(Value::Bool(res))
+    }
+
+    fn ex_lnot(&mut self) -> Result<(), VmError> {
+        let value = self.pop_bool("LNot")?;
+        self.set_result_flags(!value as i64);
+        self.push_result(Value::Bool(!value))
+    }
+
+    fn ex_cmp(&mut self, keep: bool) -> Result<(), VmError> {
+        let len = self.stack.len();
+        if len < 2 {
+            return Err(VmError::StackUnderflow(self.fault()));
+        }
+        let ordering = match (&self.stack[len - 2], &self.stack[len - 1]) {
+            (MemoryCell::Value(l), MemoryCell::Value(r)) => l.compare(r),
+            (l, r) => Err(format!("{:?} and {:?} are not comparable", l, r))
+        };
+        match ordering {
+            Ok(ord) => {
+                self.flags.equal = ord == Some(Ordering::Equal);
+                self.flags.less_than = ord == Some(Ordering::Less);
+                self.flags.great_than = ord == Some(Ordering::Greater);
+                if !keep {
+                    self.stack.truncate(len - 2);
+                }
+                self.pc += 1;
+                Ok(())
             },
+            Err(msg) => Err(VmError::TypeMismatch(self.fault(), format!("Cmp: {}", msg)))
+        }
+    }
+
+    fn ex_jump(&mut self, address: usize) -> Result<(), VmError> {
+        self.pc = address;
+        Ok(())
+    }
 
-            (l,r) => {
-                self.handle_exception(format!("Add: left and right operands must be of the same type: {:#?} + {:#?}", l, r).as_str())
+    fn ex_branch(&mut self, condition: bool, address: usize) -> Result<(), VmError> {
+        if condition {
+            self.pc = address;
+        } else {
+            self.pc += 1;
+        }
+        Ok(())
+    }
+
+    fn ex_call(&mut self, address: usize, argc: usize) -> Result<(), VmError> {
+        if self.call_stack.len() >= self.max_call_depth {
+            return Err(VmError::CallDepthExceeded(self.fault(), self.max_call_depth));
+        }
+        if self.stack.len() < argc {
+            return Err(VmError::ArgumentCount(self.fault(), argc, self.stack.len()));
+        }
+        self.call_stack.push(Frame {
+            return_address: self.pc + 1,
+            frame_pointer: self.stack.len() - argc,
+            argc
+        });
+        self.pc = address;
+        Ok(())
+    }
+
+    /// Returns to the caller, discarding the frame's arguments and locals.
+    /// If the callee pushed anything past its arguments, the top value is
+    /// the result; otherwise the call leaves nothing behind.
+    fn ex_ret(&mut self) -> Result<(), VmError> {
+        let frame = match self.call_stack.pop() {
+            Some(frame) => frame,
+            None => return Err(VmError::ReturnWithoutCall(self.fault()))
+        };
+        let result = if self.stack.len() > frame.frame_pointer + frame.argc {
+            self.stack.pop()
+        } else {
+            None
+        };
+        self.stack.truncate(frame.frame_pointer);
+        if let Some(value) = result {
+            self.stack.push(value);
+        }
+        self.pc = frame.return_address;
+        Ok(())
+    }
+
+    fn local_index(&self, n: usize) -> Result<usize, VmError> {
+        let index = self.call_stack.last()
+            .and_then(|frame| frame.frame_pointer.checked_add(n))
+            .filter(|index| *index < self.stack.len());
+        index.ok_or_else(|| VmError::InvalidLocal(self.fault(), n))
+    }
+
+    fn ex_ld_local(&mut self, n: usize) -> Result<(), VmError> {
+        let index = self.local_index(n)?;
+        self.stack.push(self.stack[index].clone());
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_st_local(&mut self, n: usize) -> Result<(), VmError> {
+        let value = self.pop()?;
+        let index = self.local_index(n)?;
+        self.stack[index] = value;
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_alloc(&mut self, len: usize) -> Result<(), VmError> {
+        if self.heap.wants_collection(len) {
+            self.collect_garbage();
+        }
+        // like malloc(0), an empty allocation is null
+        let address = match len {
+            0 => None,
+            len => Some(self.heap.alloc(len).map_err(|err| self.heap_error(err))?)
+        };
+        self.stack.push(MemoryCell::Value(Value::Address(address)));
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_gc(&mut self) -> Result<(), VmError> {
+        self.collect_garbage();
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Frees heap blocks that cannot be reached from the stack, registers or
+    /// unread input.
+    /// Frame locals live on the stack, so the call stack adds no roots.
+    pub fn collect_garbage(&mut self) -> usize {
+        let cells = self.stack.iter()
+            .chain(self.registers.iter())
+            .filter_map(|cell| match cell {
+                MemoryCell::Value(value) => Some(value),
+                _ => None
+            });
+        // messages fed by the host but not yet read may hold addresses too
+        let unread = self.inbox.values().flatten().map(|message| &message.value);
+        self.heap.collect(cells.chain(unread))
+    }
+
+    /// Pops an address, which may be null
+    fn pop_address(&mut self, op: &str) -> Result<Option<usize>, VmError> {
+        match self.pop()? {
+            MemoryCell::Value(Value::Address(address)) => Ok(address),
+            cell => Err(VmError::TypeMismatch(self.fault(), format!("{}: expected an address but found {:?}", op, cell)))
+        }
+    }
+
+    fn heap_error(&self, err: HeapError) -> VmError {
+        match err {
+            HeapError::Null => VmError::NullPointer(self.fault()),
+            HeapError::OutOfBounds(address) => VmError::HeapOutOfBounds(self.fault(), address),
+            HeapError::UseAfterFree(address) => VmError::UseAfterFree(self.fault(), address),
+            HeapError::InvalidFree(address) => VmError::InvalidFree(self.fault(), address),
+            HeapError::OutOfMemory(_) => VmError::OutOfMemory(self.fault()),
+        }
+    }
+
+    fn ex_free(&mut self) -> Result<(), VmError> {
+        // freeing null does nothing
+        if let Some(address) = self.pop_address("Free")? {
+            self.heap.free(address).map_err(|err| self.heap_error(err))?;
+        }
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_load(&mut self) -> Result<(), VmError> {
+        let address = self.pop_address("Load")?.ok_or(HeapError::Null);
+        let value = address
+            .and_then(|address| self.heap.load(address).cloned())
+            .map_err(|err| self.heap_error(err))?;
+        self.stack.push(MemoryCell::Value(value));
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_store(&mut self) -> Result<(), VmError> {
+        let value = match self.pop()? {
+            MemoryCell::Value(value) => value,
+            cell => return Err(VmError::TypeMismatch(self.fault(), format!("Store: cannot store {:?}", cell)))
+        };
+        let address = self.pop_address("Store")?.ok_or(HeapError::Null);
+        address
+            .and_then(|address| self.heap.store(address, value))
+            .map_err(|err| self.heap_error(err))?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Pops a value, raising a type mismatch for any other kind of cell
+    fn pop_value(&mut self, op: &str) -> Result<Value, VmError> {
+        match self.pop()? {
+            MemoryCell::Value(value) => Ok(value),
+            cell => Err(VmError::TypeMismatch(self.fault(), format!("{}: expected a value but found {:?}", op, cell)))
+        }
+    }
+
+    /// Pops `n` values, returning them in the order they were pushed
+    fn pop_values(&mut self, n: usize, op: &str) -> Result<Vec<Value>, VmError> {
+        if self.stack.len() < n {
+            return Err(VmError::StackUnderflow(self.fault()));
+        }
+        let mut values = Vec::with_capacity(n);
+        for _ in 0..n {
+            values.push(self.pop_value(op)?);
+        }
+        values.reverse();
+        Ok(values)
+    }
+
+    fn pop_index(&mut self, op: &str) -> Result<i64, VmError> {
+        let value = self.pop_value(op)?;
+        match value.as_integer() {
+            // a u64 too large for i64 is out of range of anything anyway
+            Some(i) => Ok(i64::try_from(i).unwrap_or(i64::MAX)),
+            None => Err(VmError::TypeMismatch(self.fault(), format!("{}: index must be an integer but found {:?}", op, value)))
+        }
+    }
+
+    /// Checks `index` against a collection of `len` items
+    fn check_index(&self, index: i64, len: usize) -> Result<usize, VmError> {
+        match usize::try_from(index) {
+            Ok(i) if i < len => Ok(i),
+            _ => Err(VmError::IndexOutOfRange(self.fault(), index, len))
+        }
+    }
+
+    fn wrong_type(&self, op: &str, expected: &str, found: &Value) -> VmError {
+        VmError::TypeMismatch(self.fault(), format!("{}: expected {} but found {:?}", op, expected, found))
+    }
+
+    fn ex_new_collection(&mut self, n: usize, op: &str, make: fn(Vec<Value>) -> Value) -> Result<(), VmError> {
+        let items = self.pop_values(n, op)?;
+        self.stack.push(MemoryCell::Value(make(items)));
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_new_map(&mut self, n: usize) -> Result<(), VmError> {
+        let items = self.pop_values(n.saturating_mul(2), "NewMap")?;
+        let mut entries: Vec<(Value, Value)> = vec![];
+        let mut items = items.into_iter();
+        while let (Some(key), Some(value)) = (items.next(), items.next()) {
+            match entries.iter_mut().find(|(k, _)| *k == key) {
+                Some(entry) => entry.1 = value,
+                None => entries.push((key, value))
             }
         }
+        self.stack.push(MemoryCell::Value(Value::Map(entries)));
         self.pc += 1;
+        Ok(())
     }
 
-    fn ex_mul(&mut self) {
-        let right = self.stack.pop();
-        let left = self.stack.pop();
-        match (left, right) {
-            (Some(MemoryCell::Value(Value::I32(l))), 
-                Some(MemoryCell::Value(Value::I32(r)))) =>
-                {
-                    let res = l * r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I32(res)) )
-                },
-            (Some(MemoryCell::Value(Value::I64(l))), 
-                Some(MemoryCell::Value(Value::I64(r)))) => {
-                    let res = l * r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I64(res)) )
-                },
+    fn ex_index(&mut self) -> Result<(), VmError> {
+        let index = self.pop_index("Index")?;
+        let item = match self.pop_value("Index")? {
+            Value::Array(items) | Value::Tuple(items) => {
+                let i = self.check_index(index, items.len())?;
+                items[i].clone()
+            },
+            Value::Bytes(bytes) => {
+                let i = self.check_index(index, bytes.len())?;
+                Value::U8(bytes[i])
+            },
+            value => return Err(self.wrong_type("Index", "an array, tuple or bytes", &value))
+        };
+        self.stack.push(MemoryCell::Value(item));
+        self.pc += 1;
+        Ok(())
+    }
 
-            (Some(MemoryCell::Value(Value::F32(l))), 
-                Some(MemoryCell::Value(Value::F32(r)))) => {
-                    let res = l * r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F32(res)) )
+    fn ex_set_index(&mut self) -> Result<(), VmError> {
+        let value = self.pop_value("SetIndex")?;
+        let index = self.pop_index("SetIndex")?;
+        match self.pop_value("SetIndex")? {
+            Value::Array(mut items) => {
+                let i = self.check_index(index, items.len())?;
+                items[i] = value;
+                self.push_result(Value::Array(items))
             },
-            (Some(MemoryCell::Value(Value::F64(l))), 
-                Some(MemoryCell::Value(Value::F64(r)))) => {
-                    let res = l * r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F64(res)) )
+            Value::Bytes(mut bytes) => {
+                let i = self.check_index(index, bytes.len())?;
+                bytes[i] = self.byte("SetIndex", &value)?;
+                self.push_result(Value::Bytes(bytes))
             },
+            value => Err(self.wrong_type("SetIndex", "an array or bytes", &value))
+        }
+    }
 
-            (l,r) => {
-                self.handle_exception(format!("Add: left and right operands must be of the same type: {:#?} + {:#?}", l, r).as_str())
-            }
+    fn ex_len(&mut self) -> Result<(), VmError> {
+        let len = match self.pop_value("Len")? {
+            Value::Array(items) | Value::Tuple(items) => items.len(),
+            Value::Map(entries) => entries.len(),
+            Value::Bytes(bytes) => bytes.len(),
+            value => return Err(self.wrong_type("Len", "an array, tuple, map or bytes", &value))
+        };
+        let len = i32::try_from(len).map_err(|_| VmError::Overflow(self.fault()))?;
+        self.stack.push(MemoryCell::Value(Value::I32(len)));
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_append(&mut self) -> Result<(), VmError> {
+        let value = self.pop_value("Append")?;
+        match self.pop_value("Append")? {
+            Value::Array(mut items) => {
+                items.push(value);
+                self.push_result(Value::Array(items))
+            },
+            Value::Bytes(mut bytes) => {
+                bytes.push(self.byte("Append", &value)?);
+                self.push_result(Value::Bytes(bytes))
+            },
+            value => Err(self.wrong_type("Append", "an array or bytes", &value))
+        }
+    }
+
+    /// An integer stored into bytes, which must fit in a u8
+    fn byte(&self, op: &str, value: &Value) -> Result<u8, VmError> {
+        match value.as_integer() {
+            Some(n) => u8::try_from(n).map_err(|_| VmError::Overflow(self.fault())),
+            None => Err(self.wrong_type(op, "a byte", value))
+        }
+    }
+
+    fn ex_map_get(&mut self) -> Result<(), VmError> {
+        let key = self.pop_value("MapGet")?;
+        let value = match self.pop_value("MapGet")? {
+            Value::Map(entries) => match entries.into_iter().find(|(k, _)| *k == key) {
+                Some((_, value)) => value,
+                None => return Err(VmError::MissingKey(self.fault(), key.to_string()))
+            },
+            value => return Err(self.wrong_type("MapGet", "a map", &value))
+        };
+        self.stack.push(MemoryCell::Value(value));
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_map_set(&mut self) -> Result<(), VmError> {
+        let value = self.pop_value("MapSet")?;
+        let key = self.pop_value("MapSet")?;
+        let mut entries = match self.pop_value("MapSet")? {
+            Value::Map(entries) => entries,
+            value => return Err(self.wrong_type("MapSet", "a map", &value))
+        };
+        match entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => entries.push((key, value))
         }
+        self.stack.push(MemoryCell::Value(Value::Map(entries)));
         self.pc += 1;
+        Ok(())
     }
 
-    fn ex_div(&mut self) {
-        let right = self.stack.pop();
-        let left = self.stack.pop();
+    fn pop_string(&mut self, op: &str) -> Result<String, VmError> {
+        match self.pop_value(op)? {
+            Value::String(s) => Ok(s),
+            value => Err(self.wrong_type(op, "a string", &value))
+        }
+    }
+
+    /// Pops a string, or a char as a one char string
+    fn pop_text(&mut self, op: &str) -> Result<String, VmError> {
+        let value = self.pop_value(op)?;
+        self.text(op, value)
+    }
+
+    fn text(&self, op: &str, value: Value) -> Result<String, VmError> {
+        match value {
+            Value::String(s) => Ok(s),
+            Value::Char(c) => Ok(c.to_string()),
+            value => Err(self.wrong_type(op, "a string or char", &value))
+        }
+    }
+
+    /// Converts a char count to an I32 for the stack
+    fn char_count(&self, n: usize) -> Result<Value, VmError> {
+        i32::try_from(n).map(Value::I32).map_err(|_| VmError::Overflow(self.fault()))
+    }
+
+    fn push_result(&mut self, value: Value) -> Result<(), VmError> {
+        self.stack.push(MemoryCell::Value(value));
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_concat(&mut self) -> Result<(), VmError> {
+        let right = self.pop_value("Concat")?;
+        let left = self.pop_value("Concat")?;
         match (left, right) {
-            (Some(MemoryCell::Value(Value::I32(l))), 
-                Some(MemoryCell::Value(Value::I32(r)))) =>
-                {
-                    let res = l / r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I32(res)) )
+            (Value::Bytes(mut left), Value::Bytes(right)) => {
+                left.extend(right);
+                self.push_result(Value::Bytes(left))
+            },
+            (left, right) => {
+                let joined = self.text("Concat", left)? + &self.text("Concat", right)?;
+                self.push_result(Value::String(joined))
+            }
+        }
+    }
+
+    fn ex_substr(&mut self) -> Result<(), VmError> {
+        let len = self.pop_index("Substr")?;
+        let start = self.pop_index("Substr")?;
+        let s = self.pop_string("Substr")?;
+        let count = s.chars().count();
+        // indexes are in chars and the range may end one past the last char
+        let start = self.check_index(start, count + 1)?;
+        if len < 0 {
+            return Err(VmError::IndexOutOfRange(self.fault(), len, count));
+        }
+        match (start as i64).checked_add(len) {
+            Some(end) => self.check_index(end, count + 1)?,
+            None => return Err(VmError::IndexOutOfRange(self.fault(), len, count))
+        };
+        let sub: String = s.chars().skip(start).take(len as usize).collect();
+        self.push_result(Value::String(sub))
+    }
+
+    fn ex_str_len(&mut self) -> Result<(), VmError> {
+        let s = self.pop_string("StrLen")?;
+        let len = self.char_count(s.chars().count())?;
+        self.push_result(len)
+    }
+
+    fn ex_char_at(&mut self) -> Result<(), VmError> {
+        let index = self.pop_index("CharAt")?;
+        let s = self.pop_string("CharAt")?;
+        let i = self.check_index(index, s.chars().count())?;
+        self.push_result(Value::Char(s.chars().nth(i).unwrap_or_default()))
+    }
+
+    fn ex_str_cmp(&mut self) -> Result<(), VmError> {
+        let right = self.pop_string("StrCmp")?;
+        let left = self.pop_string("StrCmp")?;
+        let ordering = left.cmp(&right);
+        self.flags.equal = ordering == Ordering::Equal;
+        self.flags.less_than = ordering == Ordering::Less;
+        self.flags.great_than = ordering == Ordering::Greater;
+        self.push_result(Value::I32(ordering as i32))
+    }
+
+    fn ex_map_str(&mut self, op: &str, map: fn(&str) -> String) -> Result<(), VmError> {
+        let s = self.pop_string(op)?;
+        self.push_result(Value::String(map(&s)))
+    }
+
+    fn ex_split(&mut self) -> Result<(), VmError> {
+        let separator = self.pop_text("Split")?;
+        let s = self.pop_string("Split")?;
+        let parts: Vec<Value> = if separator.is_empty() {
+            s.chars().map(|c| Value::String(c.to_string())).collect()
+        } else {
+            s.split(separator.as_str()).map(|part| Value::String(part.to_string())).collect()
+        };
+        self.push_result(Value::Array(parts))
+    }
+
+    fn ex_find(&mut self) -> Result<(), VmError> {
+        let needle = self.pop_text("Find")?;
+        let s = self.pop_string("Find")?;
+        let index = match s.find(needle.as_str()) {
+            Some(byte) => self.char_count(s[..byte].chars().count())?,
+            None => Value::I32(-1)
+        };
+        self.push_result(index)
+    }
+
+    /// Replaces each `{}` in the format string with the next argument;
+    /// `{{` and `}}` stand for literal braces
+    fn ex_format(&mut self, n: usize) -> Result<(), VmError> {
+        let args = self.pop_values(n, "Format")?;
+        let format = self.pop_string("Format")?;
+        let mut args = args.into_iter();
+        let mut out = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    out.push(c);
                 },
-            (Some(MemoryCell::Value(Value::I64(l))), 
-                Some(MemoryCell::Value(Value::I64(r)))) => {
-                    let res = l / r;
-                    self.flags.zero = res == 0;
-                    self.flags.pos = res > 0;
-                    self.flags.neg = res < 0;
-                    self.stack.push(MemoryCell::Value( Value::I64(res)) )
+                ('{', Some('}')) => {
+                    chars.next();
+                    match args.next() {
+                        Some(arg) => out.push_str(&arg.to_string()),
+                        None => return Err(VmError::TypeMismatch(self.fault(),
+                            format!("Format: {:?} has more placeholders than the {} arguments", format, n)))
+                    }
                 },
+                ('{', _) | ('}', _) => return Err(VmError::TypeMismatch(self.fault(),
+                    format!("Format: unmatched '{}' in {:?}", c, format))),
+                _ => out.push(c)
+            }
+        }
+        if args.next().is_some() {
+            return Err(VmError::TypeMismatch(self.fault(),
+                format!("Format: {:?} has fewer placeholders than the {} arguments", format, n)));
+        }
+        self.push_result(Value::String(out))
+    }
 
-            (Some(MemoryCell::Value(Value::F32(l))), 
-                Some(MemoryCell::Value(Value::F32(r)))) => {
-                    let res = l / r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F32(res)) )
-            },
-            (Some(MemoryCell::Value(Value::F64(l))), 
-                Some(MemoryCell::Value(Value::F64(r)))) => {
-                    let res = l / r;
-                    self.flags.zero = res == 0.0;
-                    self.flags.pos = res > 0.0;
-                    self.flags.neg = res < 0.0;
-                    self.stack.push(MemoryCell::Value( Value::F64(res)) )
-            },
+    fn ex_conv(&mut self, target: ValueType) -> Result<(), VmError> {
+        let value = self.pop_value("Conv")?;
+        match value.convert(target) {
+            Ok(converted) => self.push_result(converted),
+            Err(ConvError::Overflow) => Err(VmError::Overflow(self.fault())),
+            Err(ConvError::Invalid(msg)) => Err(VmError::ConversionFailed(self.fault(), msg))
+        }
+    }
 
-            (l,r) => {
-                self.handle_exception(format!("Add: left and right operands must be of the same type: {:#?} + {:#?}", l, r).as_str())
-            }
+    fn ex_type_of(&mut self) -> Result<(), VmError> {
+        let value = self.pop_value("TypeOf")?;
+        let name = Value::String(value.value_type().name().to_string());
+        self.push_result(Value::Symbol(Rc::new(name)))
+    }
+
+    fn ex_push(&mut self, value: Value) -> Result<(), VmError> {
+        self.stack.push(MemoryCell::Value( value ));
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn ex_pop(&mut self) -> Result<(), VmError> {
+        self.pop()?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Position in the stack of the nth value below the top
+    fn stack_slot(&self, n: usize) -> Result<usize, VmError> {
+        if n < self.stack.len() {
+            Ok(self.stack.len() - 1 - n)
+        } else {
+            Err(VmError::StackUnderflow(self.fault()))
         }
+    }
+
+    fn ex_pick(&mut self, n: usize) -> Result<(), VmError> {
+        let slot = self.stack_slot(n)?;
+        self.stack.push(self.stack[slot].clone());
         self.pc += 1;
+        Ok(())
     }
 
-    fn ex_jump(&mut self, address: usize) {
-        self.pc = address;
+    fn ex_dup2(&mut self) -> Result<(), VmError> {
+        let slot = self.stack_slot(1)?;
+        self.stack.extend_from_within(slot..);
+        self.pc += 1;
+        Ok(())
     }
 
+    fn ex_depth(&mut self) -> Result<(), VmError> {
+        let depth = i32::try_from(self.stack.len()).map_err(|_| VmError::Overflow(self.fault()))?;
+        self.push_result(Value::I32(depth))
+    }
 
-    fn ex_push(&mut self, value: Value) {
-        self.stack.push(MemoryCell::Value( value ));
+    fn ex_clear(&mut self) -> Result<(), VmError> {
+        self.stack.clear();
         self.pc += 1;
+        Ok(())
     }
 
-    fn ex_pop(&mut self) {
-        self.stack.pop();
+    fn ex_roll(&mut self, n: usize) -> Result<(), VmError> {
+        let slot = self.stack_slot(n)?;
+        let cell = self.stack.remove(slot);
+        self.stack.push(cell);
         self.pc += 1;
+        Ok(())
     }
 
 
-    fn ex_out(&mut self, port: usize, message: Message) {
-        unsafe {
-            match &mut message_handler {
-                Some(handler) => handler.send(port, message),
-                None => self.handle_exception("No communication channel available")
-            }
+    fn send(&mut self, port: usize, message: Message) -> Result<(), VmError> {
+        let device = match self.ports.get(port) {
+            Some(device) => device.clone(),
+            None => return Err(VmError::NoDevice(self.fault(), port))
+        };
+        let result = device.borrow_mut().send(message);
+        result.map_err(|msg| VmError::Device(self.fault(), port, msg))
+    }
+
+    fn ex_out(&mut self, port: usize, message: Message) -> Result<(), VmError> {
+        self.send(port, message)?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Sends the top of the stack from this VM, formatted as text if `as_text`
+    fn ex_out_top(&mut self, port: usize, as_text: bool) -> Result<(), VmError> {
+        let value = match self.pop()? {
+            MemoryCell::Value(value) if as_text => Value::String(value.to_string()),
+            MemoryCell::Value(value) => value,
+            cell => return Err(VmError::TypeMismatch(self.fault(), format!("cannot send {:?}", cell)))
+        };
+        self.send(port, Message { from: self.id, to: 0, value })?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Takes the next message for `port`, from the inbox or else the device
+    fn receive(&mut self, port: usize) -> Option<Message> {
+        if let Some(message) = self.inbox.get_mut(&port).and_then(|queue| queue.pop_front()) {
+            return Some(message);
+        }
+        let device = self.ports.get(port)?.clone();
+        let message = device.borrow_mut().receive();
+        message
+    }
+
+    fn ex_in(&mut self, port: usize, blocking: bool) -> Result<(), VmError> {
+        match self.receive(port) {
+            Some(message) => {
+                self.stack.push(MemoryCell::Value(message.value));
+                self.pc += 1;
+                Ok(())
+            },
+            // leave the pc on the In so it is retried when the host resumes
+            None if blocking => {
+                self.waiting = Some(port);
+                Ok(())
+            },
+            None => Err(VmError::NoInput(self.fault(), port))
+        }
+    }
+
+    fn ex_try_in(&mut self, port: usize) -> Result<(), VmError> {
+        match self.receive(port) {
+            Some(message) => {
+                self.flags.zero = false;
+                self.stack.push(MemoryCell::Value(message.value));
+            },
+            None => self.flags.zero = true
         }
         self.pc += 1;
+        Ok(())
     }
 
-    fn ex_halt(&mut self) {
+    fn ex_halt(&mut self) -> Result<(), VmError> {
         self.running = false;
+        Ok(())
     }
 
-    fn ex_dump(&mut self) {
+    fn ex_dump(&mut self) -> Result<(), VmError> {
         println!("\n----------- Start Processor Dump -------------");
         println!("{:#?}", self);
+        let stats = self.heap.stats();
+        println!("\nheap: {} live blocks, {} live cells, {} cells total, {} allocations, {} frees",
+                 stats.live_blocks, stats.live_cells, stats.capacity, stats.allocations, stats.frees);
+        let gc = self.heap.gc_stats();
+        println!("gc: {} collections, {} blocks ({} bytes) freed, {:?} total pause",
+                 gc.collections, gc.blocks_freed, gc.bytes_freed, gc.total_pause);
         println!("\n----------- End Processor Dump -------------");
 
         self.pc += 1;
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        while self.running {
-            self.fetch();
-            self.decode();
+    /// Executes exactly one fetch/decode cycle. An error stops the VM.
+    pub fn step(&mut self) -> Result<HaltReason, VmError> {
+        if !self.running {
+            return Ok(HaltReason::Halted);
+        }
+        match self.fetch().and_then(|_| self.decode()) {
+            Ok(()) if self.running => match self.waiting.take() {
+                Some(port) => Ok(HaltReason::WaitingForInput(port)),
+                None => Ok(HaltReason::Stepped)
+            },
+            Ok(()) => Ok(HaltReason::Halted),
+            Err(err) => {
+                self.running = false;
+                Err(err)
+            }
         }
     }
-}
-
 
+    pub fn run(&mut self) -> Result<HaltReason, VmError> {
+        loop {
+            match self.step()? {
+                HaltReason::Stepped => continue,
+                reason => return Ok(reason)
+            }
+        }
+    }
 
+    /// Lowers the program in memory for `run_lowered`
+    pub fn lower(&self) -> Program {
+        Program::lower(&self.memory)
+    }
 
+    /// Like `run`, but executes `program`, which must have been lowered from
+    /// this VM's memory after the last change to it. The VM state is shared
+    /// with `step` and `run`, so a program waiting for input can be resumed
+    /// by either.
+    pub fn run_lowered(&mut self, program: &Program) -> Result<HaltReason, VmError> {
+        self.cur_instruction = None;
+        while self.running {
+            let result = match program.code().get(self.pc) {
+                Some(&op) => self.dispatch(program, op),
+                None => Err(VmError::PcOutOfBounds(self.fault()))
+            };
+            if let Err(err) = result {
+                self.running = false;
+                return Err(err);
+            }
+            if let Some(port) = self.waiting.take() {
+                return Ok(HaltReason::WaitingForInput(port));
+            }
+        }
+        Ok(HaltReason::Halted)
+    }
+}
@@ -0,0 +1,405 @@
+//! Interactive step debugger
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::Path,
+};
+
+use rmv_asm::{assemble, disassemble_range, disassembler::format_value, parse_value};
+use rusty_vm::rvm::{
+    devices::{self, Clock, Console, Random},
+    error::HaltReason,
+    port::MessageHandler,
+    vm::{MemoryCell, Message, RustyVM, REGISTER_COUNT},
+};
+
+/// Number of cells `disasm` shows on each side of the pc by default
+const DISASM_CONTEXT: usize = 5;
+
+const HELP: &str = "\
+load <file>                 load assembly source or a .rvmi image
+reset                       restart the loaded program
+step [n]                    execute n instructions (default 1), entering calls
+next                        execute one instruction, stepping over calls
+continue                    run until a breakpoint, watchpoint, halt or error
+feed <port> <value>         queue input for the program to read from a port
+break <address|label>       stop before executing the instruction
+delete <address|label>      remove a breakpoint
+watch <rN|depth>            stop when a register or the stack depth changes
+unwatch <rN|depth>          remove a watchpoint
+info                        list breakpoints and watchpoints
+print stack|regs|flags|heap|calls
+disasm [n]                  disassemble n cells around the pc
+help                        show this text
+quit                        leave the debugger";
+
+/// Something whose value is checked after every instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Watchpoint {
+    Register(usize),
+    StackDepth,
+}
+
+/// Runs a program one instruction at a time under user control
+pub struct Debugger {
+    program: RustyVM,       // pristine copy used by reset
+    vm: RustyVM,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<Watchpoint>,
+}
+
+impl Debugger {
+    pub fn new(mut program: RustyVM) -> Debugger {
+        // In suspends the program until input is fed rather than failing
+        program.set_blocking_input(true);
+        let mut vm = program.clone();
+        vm.reset();
+        Debugger {
+            program,
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn vm(&self) -> &RustyVM {
+        &self.vm
+    }
+
+    /// Loads a program from a binary image (`.rvmi`) or assembly source with
+    /// the standard devices attached. Console input comes from `feed` because
+    /// stdin belongs to the debugger.
+    pub fn load(path: &str) -> Result<Debugger, String> {
+        let mut program = if Path::new(path).extension().is_some_and(|ext| ext == "rvmi") {
+            let image = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+            RustyVM::load_image(&image).map_err(|err| format!("{}: {}", path, err))?
+        } else {
+            let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+            assemble(&source).map_err(|err| err.render(path, &source))?.vm().clone()
+        };
+        program.attach(devices::CONSOLE_PORT, MessageHandler {
+            sender: Box::new(|msg: Message| print!("{}", msg.get_message())),
+            receiver: Box::new(|| None),
+        });
+        program.attach(devices::ERROR_PORT, Console::stderr());
+        program.attach(devices::CLOCK_PORT, Clock::new());
+        program.attach(devices::RANDOM_PORT, Random::from_time());
+        Ok(Debugger::new(program))
+    }
+
+    /// Executes one command line and returns the text to show the user
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => Ok(String::new()),
+            ["help" | "h"] => Ok(String::from(HELP)),
+            ["load" | "l", path] => {
+                *self = Debugger::load(path)?;
+                Ok(format!("loaded {} cells from {}", self.vm.memory().len(), path))
+            },
+            ["reset"] => {
+                self.vm = self.program.clone();
+                self.vm.reset();
+                Ok(self.location())
+            },
+            ["step" | "s"] => Ok(self.step(1)),
+            ["step" | "s", n] => Ok(self.step(parse_count(n)?)),
+            ["next" | "n"] => Ok(self.next()),
+            ["continue" | "c"] => Ok(self.run_until(|_| false)),
+            ["feed" | "f", port, _, ..] => {
+                let port = port.parse::<usize>().map_err(|_| format!("invalid port '{}'", port))?;
+                // the literal is the rest of the line, keeping the spacing inside strings
+                let literal = line.trim().splitn(3, char::is_whitespace).nth(2).unwrap_or("");
+                let value = parse_value(literal.trim()).map_err(|err| err.message)?;
+                self.vm.feed(port, Message { from: 0, to: 0, value });
+                Ok(format!("queued input on port {}", port))
+            },
+            ["break" | "b", target] => {
+                let address = self.address(target)?;
+                self.breakpoints.insert(address);
+                Ok(format!("breakpoint at {:04}", address))
+            },
+            ["delete" | "d", target] => {
+                let address = self.address(target)?;
+                if !self.breakpoints.remove(&address) {
+                    return Err(format!("no breakpoint at {:04}", address));
+                }
+                Ok(format!("deleted breakpoint at {:04}", address))
+            },
+            ["watch" | "w", target] => {
+                let watch = parse_watchpoint(target)?;
+                self.watchpoints.insert(watch);
+                Ok(format!("watching {} = {}", target, self.watched(watch)))
+            },
+            ["unwatch", target] => {
+                let watch = parse_watchpoint(target)?;
+                if !self.watchpoints.remove(&watch) {
+                    return Err(format!("{} is not watched", target));
+                }
+                Ok(format!("stopped watching {}", target))
+            },
+            ["info" | "i"] => Ok(self.info()),
+            ["print" | "p", what] => self.print(what),
+            ["disasm"] => Ok(self.disasm(DISASM_CONTEXT)),
+            ["disasm", n] => Ok(self.disasm(parse_count(n)?)),
+            [command, ..] => Err(format!("unknown command '{}', try 'help'", command)),
+        }
+    }
+
+    fn step(&mut self, count: usize) -> String {
+        let mut remaining = count;
+        self.run_until(|_| {
+            remaining -= 1;
+            remaining == 0
+        })
+    }
+
+    /// Steps one instruction, running a call to completion
+    fn next(&mut self) -> String {
+        let depth = self.vm.call_stack().len();
+        self.run_until(|vm| vm.call_stack().len() <= depth)
+    }
+
+    /// Steps until `done` returns true after an instruction, or a
+    /// breakpoint, watchpoint, halt or error stops the program
+    fn run_until(&mut self, mut done: impl FnMut(&RustyVM) -> bool) -> String {
+        if !self.vm.is_running() {
+            return String::from("the program is not running, use 'reset' to restart it");
+        }
+        let mut first = true;
+        loop {
+            if !first && self.breakpoints.contains(&self.vm.pc()) {
+                return format!("breakpoint\n{}", self.location());
+            }
+            first = false;
+
+            let before: Vec<String> = self.watchpoints.iter().map(|w| self.watched(*w)).collect();
+            match self.vm.step() {
+                Ok(HaltReason::Stepped) => (),
+                Ok(HaltReason::Halted) => return format!("halted at {:04}", self.vm.pc()),
+                Ok(HaltReason::WaitingForInput(port)) =>
+                    return format!("waiting for input on port {}, use 'feed {} <value>'\n{}", port, port, self.location()),
+                Err(err) => return err.to_string(),
+            }
+
+            let changes: Vec<String> = self.watchpoints.iter().zip(before)
+                .map(|(w, old)| (watch_name(*w), old, self.watched(*w)))
+                .filter(|(_, old, new)| old != new)
+                .map(|(name, old, new)| format!("{} changed: {} -> {}", name, old, new))
+                .collect();
+            if !changes.is_empty() {
+                return format!("{}\n{}", changes.join("\n"), self.location());
+            }
+            if done(&self.vm) {
+                return self.location();
+            }
+        }
+    }
+
+    /// Resolves a breakpoint given as an address or a label
+    fn address(&self, target: &str) -> Result<usize, String> {
+        match target.parse::<usize>() {
+            Ok(address) if address < self.vm.memory().len() => Ok(address),
+            Ok(address) => Err(format!("address {} is outside the program", address)),
+            Err(_) => self.vm.symbols().get(target)
+                .copied()
+                .ok_or_else(|| format!("unknown label '{}'", target)),
+        }
+    }
+
+    fn watched(&self, watch: Watchpoint) -> String {
+        match watch {
+            Watchpoint::Register(reg) => format_cell(&self.vm.registers()[reg]),
+            Watchpoint::StackDepth => self.vm.stack().len().to_string(),
+        }
+    }
+
+    /// The instruction about to be executed
+    fn location(&self) -> String {
+        let pc = self.vm.pc();
+        match self.vm.memory().get(pc) {
+            Some(_) => disassemble_range(&self.vm, pc..pc + 1).trim_end().to_string(),
+            None => format!("pc {:04} is outside the program", pc),
+        }
+    }
+
+    fn info(&self) -> String {
+        let mut lines = vec![];
+        for address in &self.breakpoints {
+            lines.push(format!("breakpoint at {:04}", address));
+        }
+        for watch in &self.watchpoints {
+            lines.push(format!("watching {} = {}", watch_name(*watch), self.watched(*watch)));
+        }
+        if lines.is_empty() {
+            lines.push(String::from("no breakpoints or watchpoints"));
+        }
+        lines.join("\n")
+    }
+
+    fn print(&self, what: &str) -> Result<String, String> {
+        let lines: Vec<String> = match what {
+            "stack" => self.vm.stack().iter().enumerate().rev()
+                .map(|(i, cell)| format!("{:4}: {}", i, format_cell(cell)))
+                .collect(),
+            "regs" | "registers" => self.vm.registers().iter().enumerate()
+                .filter(|(_, cell)| **cell != MemoryCell::Empty)
+                .map(|(i, cell)| format!("r{:<3} = {}", i, format_cell(cell)))
+                .collect(),
+            "flags" => {
+                let flags = self.vm.flags();
+                vec![format!("zero={} neg={} pos={} equal={} less_than={} great_than={} overflow={} carry={} non_finite={}",
+                             flags.zero as u8, flags.neg as u8, flags.pos as u8, flags.equal as u8,
+                             flags.less_than as u8, flags.great_than as u8, flags.overflow as u8, flags.carry as u8,
+                             flags.non_finite as u8)]
+            },
+            "heap" => self.vm.heap().blocks()
+                .map(|(base, cells)| format!("@{:<4} {}", base,
+                                             cells.iter().map(format_value).collect::<Vec<_>>().join(", ")))
+                .collect(),
+            "calls" => self.vm.call_stack().iter().enumerate().rev()
+                .map(|(i, frame)| format!("#{} return to {:04}, frame at {}, {} args",
+                                          i, frame.return_address, frame.frame_pointer, frame.argc))
+                .collect(),
+            _ => return Err(format!("cannot print '{}', expected stack, regs, flags, heap or calls", what)),
+        };
+        if lines.is_empty() {
+            Ok(format!("{} is empty", what))
+        } else {
+            Ok(lines.join("\n"))
+        }
+    }
+
+    /// Disassembles `context` cells on each side of the pc, marking the pc
+    fn disasm(&self, context: usize) -> String {
+        let pc = self.vm.pc();
+        let start = pc.saturating_sub(context);
+        let end = pc.saturating_add(context).saturating_add(1).min(self.vm.memory().len());
+        let marker = format!("; {:04}", pc);
+        disassemble_range(&self.vm, start..end)
+            .lines()
+            .map(|line| match line.strip_prefix("    ") {
+                Some(rest) if line.ends_with(&marker) => format!("=>  {}", rest),
+                _ => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn parse_count(text: &str) -> Result<usize, String> {
+    match text.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("expected a positive count but found '{}'", text)),
+    }
+}
+
+fn parse_watchpoint(text: &str) -> Result<Watchpoint, String> {
+    if text == "depth" {
+        return Ok(Watchpoint::StackDepth);
+    }
+    match text.strip_prefix('r').and_then(|n| n.parse::<usize>().ok()) {
+        Some(reg) if reg < REGISTER_COUNT => Ok(Watchpoint::Register(reg)),
+        _ => Err(format!("cannot watch '{}', expected a register r0-r{} or depth", text, REGISTER_COUNT - 1)),
+    }
+}
+
+fn watch_name(watch: Watchpoint) -> String {
+    match watch {
+        Watchpoint::Register(reg) => format!("r{}", reg),
+        Watchpoint::StackDepth => String::from("depth"),
+    }
+}
+
+fn format_cell(cell: &MemoryCell) -> String {
+    match cell {
+        MemoryCell::Value(value) => format_value(value),
+        MemoryCell::Empty => String::from("<empty>"),
+        other => format!("{:?}", other),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "
+        ld_imm r0, 3
+        push 1
+        call twice, 1
+        st r1
+    loop:
+        push 1
+        st r2
+        halt
+    twice:
+        ld_local 0
+        ld_local 0
+        add
+        ret
+    ";
+
+    fn debugger() -> Debugger {
+        Debugger::new(assemble(PROGRAM).unwrap().vm().clone())
+    }
+
+    #[test]
+    fn breakpoints_stop_at_labels() {
+        let mut debugger = debugger();
+        debugger.execute("break loop").unwrap();
+        let output = debugger.execute("continue").unwrap();
+        assert!(output.starts_with("breakpoint\nloop:"), "{}", output);
+        assert_eq!(4, debugger.vm().pc());
+
+        assert_eq!(Ok(String::from("halted at 0006")), debugger.execute("continue"));
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut debugger = debugger();
+        debugger.execute("step 2").unwrap();
+        assert_eq!(2, debugger.vm().pc());
+
+        debugger.execute("next").unwrap();
+        assert_eq!(3, debugger.vm().pc());
+        assert_eq!(Ok(String::from("   0: 2")), debugger.execute("print stack"));
+
+        debugger.execute("reset").unwrap();
+        debugger.execute("step 3").unwrap();
+        assert_eq!(7, debugger.vm().pc());
+        assert_eq!(1, debugger.vm().call_stack().len());
+    }
+
+    #[test]
+    fn watchpoints_report_changes() {
+        let mut debugger = debugger();
+        debugger.execute("watch r1").unwrap();
+        let output = debugger.execute("continue").unwrap();
+        assert!(output.starts_with("r1 changed: <empty> -> 2\n"), "{}", output);
+        assert_eq!(4, debugger.vm().pc());
+
+        assert!(debugger.execute("watch r16").is_err());
+        assert!(debugger.execute("break nowhere").is_err());
+    }
+
+    #[test]
+    fn input_waits_until_fed() {
+        let mut debugger = Debugger::new(assemble("in 1\nhalt").unwrap().vm().clone());
+        let output = debugger.execute("continue").unwrap();
+        assert!(output.starts_with("waiting for input on port 1"), "{}", output);
+
+        debugger.execute("feed 1 \"two  words\"").unwrap();
+        debugger.execute("continue").unwrap();
+        assert_eq!(Ok(String::from("   0: \"two  words\"")), debugger.execute("print stack"));
+    }
+
+    #[test]
+    fn disasm_clamps_its_context() {
+        let mut debugger = debugger();
+        let output = debugger.execute("disasm 18446744073709551615").unwrap();
+        assert!(output.starts_with("=>  ld_imm r0, 3"), "{}", output);
+        assert_eq!(output, debugger.execute("disasm 100").unwrap());
+    }
+}
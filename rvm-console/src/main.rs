@@ -1,32 +1,29 @@
+mod debugger;
+
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    process,
+};
 
 use rusty_vm::rvm:: {
-    self, 
-    vm::{self,
+    vm::{
         Value, 
-        message_handler, 
-        MessageHandler,
         Message,
-
+        RustyVM,
     }, 
     builder};
 
+use debugger::Debugger;
 
 
-fn main() {
+/// The program debugged when no file is given on the command line
+fn demo_program() -> RustyVM {
     let mut builder = builder::VMBuilder::new();
 
-    unsafe {
-        message_handler = Some(
-            MessageHandler {
-                sender: Box::new(|port: usize, msg: Message| print!("{}", msg.get_message() )),
-                receiver: Box::new(|port: usize| Some(Message { from: 1, to: 1, value: Value::F32(42.0) }))
-            }
-        );
-    }
-
-    builder
+    let result = builder
+        .standard_devices()
         .label("Start")
-        .jump("End")
         .push(Value::I32(21))
         .push(Value::I32(21))
         //.dump()
@@ -37,13 +34,56 @@ fn main() {
         .div()
         .push(Value::I32(4))
         .sub()
-        .out(0, Message { from: 0, to: 0, value: Value::String(String::from("This is a message ")) })
-        .out(0, Message {from: 0, to: 0, value: Value::F64(27.56)})
+        .out(0, Message { from: 0, to: 0, value: Value::String(String::from("The result is ")) })
+        .print()
         .out(0, Message { from: 0, to: 0, value: Value::String(String::from("\n")) })
         .label("End")
-        .dump()
         .halt()
-        .build()
-        .start();
+        .build();
 
+    match result {
+        Ok(program) => program.vm().clone(),
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut debugger = match env::args().nth(1) {
+        Some(path) => Debugger::load(&path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        }),
+        None => Debugger::new(demo_program()),
+    };
+
+    println!("Rusty VM debugger, {} cells loaded, type 'help' for a list of commands", debugger.vm().memory().len());
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(rvm) ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        // an empty line repeats the previous command
+        let line = match line.trim() {
+            "" => last.clone(),
+            line => line.to_string(),
+        };
+        if matches!(line.as_str(), "quit" | "q") {
+            break;
+        }
+
+        match debugger.execute(&line) {
+            Ok(output) if output.is_empty() => (),
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("error: {}", err),
+        }
+        last = line;
+    }
 }